@@ -1,27 +1,111 @@
-use std::u8;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+#[cfg(any(feature = "trace", test))]
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::rc::Rc;
 
+use crate::errors::LoxError;
+use crate::scanner::Span;
+
+#[derive(Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
 pub enum OpCode {
     OpConstant = 0,
+    OpConstantLong,
+    OpNil,
+    OpTrue,
+    OpFalse,
+    OpPop,
+    OpGetLocal,
+    OpSetLocal,
+    OpGetGlobal,
+    OpDefineGlobal,
+    OpSetGlobal,
     OpAdd,
     OpSubtract,
     OpMultiply,
     OpDevide,
     OpNegate,
+    OpNot,
+    OpEqual,
+    OpGreater,
+    OpLess,
+    OpPrint,
+    OpJump,
+    OpJumpIfFalse,
+    OpLoop,
+    OpGetUpvalue,
+    OpSetUpvalue,
+    OpGetProperty,
+    OpSetProperty,
+    OpGetSuper,
+    OpCall,
+    OpInvoke,
+    OpSuperInvoke,
+    OpClosure,
+    OpCloseUpvalue,
     OpReturn,
+    OpClass,
+    OpInherit,
+    OpMethod,
+    // Prefix giving the constant operand of the next instruction 24 bits.
+    OpWide,
 }
 
-impl From<u8> for OpCode {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => OpCode::OpConstant,
-            1 => OpCode::OpAdd,
-            2 => OpCode::OpSubtract,
-            3 => OpCode::OpMultiply,
-            4 => OpCode::OpDevide,
-            5 => OpCode::OpNegate,
-            6 => OpCode::OpReturn,
-            _ => unimplemented!("unimplemented opcode"),
-        }
+// Every opcode indexed by its byte, the round trip test keeps it in step with the enum.
+const OPCODES: [OpCode; 39] = [
+    OpCode::OpConstant,
+    OpCode::OpConstantLong,
+    OpCode::OpNil,
+    OpCode::OpTrue,
+    OpCode::OpFalse,
+    OpCode::OpPop,
+    OpCode::OpGetLocal,
+    OpCode::OpSetLocal,
+    OpCode::OpGetGlobal,
+    OpCode::OpDefineGlobal,
+    OpCode::OpSetGlobal,
+    OpCode::OpAdd,
+    OpCode::OpSubtract,
+    OpCode::OpMultiply,
+    OpCode::OpDevide,
+    OpCode::OpNegate,
+    OpCode::OpNot,
+    OpCode::OpEqual,
+    OpCode::OpGreater,
+    OpCode::OpLess,
+    OpCode::OpPrint,
+    OpCode::OpJump,
+    OpCode::OpJumpIfFalse,
+    OpCode::OpLoop,
+    OpCode::OpGetUpvalue,
+    OpCode::OpSetUpvalue,
+    OpCode::OpGetProperty,
+    OpCode::OpSetProperty,
+    OpCode::OpGetSuper,
+    OpCode::OpCall,
+    OpCode::OpInvoke,
+    OpCode::OpSuperInvoke,
+    OpCode::OpClosure,
+    OpCode::OpCloseUpvalue,
+    OpCode::OpReturn,
+    OpCode::OpClass,
+    OpCode::OpInherit,
+    OpCode::OpMethod,
+    OpCode::OpWide,
+];
+
+impl TryFrom<u8> for OpCode {
+    type Error = LoxError;
+
+    fn try_from(value: u8) -> Result<Self, LoxError> {
+        OPCODES
+            .get(value as usize)
+            .copied()
+            .ok_or(LoxError::InvalidBytecode(value))
     }
 }
 
@@ -33,9 +117,71 @@ impl From<OpCode> for u8 {
 // Chunks
 //
 
+// OpConstantLong and instructions prefixed with OpWide take a 24 bit operand.
+pub const CONSTANTS_LONG_MAX: usize = 1 << 24;
+
+// Little endian base 128, seven bits per byte with the high bit set on all but the last.
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes.next()?;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+// Maps the difference between two positions to an unsigned number that stays small
+// for small steps in either direction.
+fn zigzag(value: usize, base: usize) -> usize {
+    if value >= base {
+        (value - base) << 1
+    } else {
+        ((base - value) << 1) - 1
+    }
+}
+
+fn unzigzag(delta: Option<usize>, base: usize) -> usize {
+    let delta = delta.expect("position records are written whole");
+    if delta & 1 == 0 {
+        base + (delta >> 1)
+    } else {
+        base - ((delta + 1) >> 1)
+    }
+}
+
+// Decodes the big endian operand of OpConstantLong and OpWide instructions.
+pub fn read_u24(bytes: &[u8]) -> usize {
+    u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize
+}
+
+// The token code was compiled from and the offset of its first byte.
+#[derive(Clone, Copy, Default)]
+struct Position {
+    start: usize,
+    line: usize,
+    span: Span,
+}
+
 pub struct Chunk {
     pub code: Vec<u8>,
-    lines: Vec<usize>,
+    // A record for every change of source position, usually one per instruction. Each
+    // record holds the offset, line and span start differences to the previous one and
+    // the length of the span as varints, so most records take four bytes.
+    positions: Vec<u8>,
+    // Where the next record is taken relative to.
+    last_position: Option<Position>,
     constants: ValueArray,
 }
 
@@ -43,78 +189,491 @@ impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: Vec::new(),
-            lines: Vec::new(),
+            positions: Vec::new(),
+            last_position: None,
             constants: ValueArray::new(),
         }
     }
 
-    pub fn write(&mut self, byte: u8, line: usize) {
+    pub fn write(&mut self, byte: u8, line: usize, span: Span) {
+        let position = Position {
+            start: self.code.len(),
+            line,
+            span,
+        };
+        match self.last_position {
+            Some(last) if last.line == line && last.span == span => {}
+            last => {
+                let last = last.unwrap_or_default();
+                write_varint(&mut self.positions, position.start - last.start);
+                write_varint(&mut self.positions, zigzag(line, last.line));
+                write_varint(&mut self.positions, zigzag(span.start, last.span.start));
+                write_varint(&mut self.positions, span.end - span.start);
+                self.last_position = Some(position);
+            }
+        }
         self.code.push(byte);
-        self.lines.push(line);
     }
 
     pub fn read(&self, ip: usize) -> u8 {
         self.code[ip]
     }
 
-    pub fn disassemble_chunk(&self, name: &str) {
-        println!("== {} ==", name);
+    // The source of the token the byte was compiled from.
+    pub fn get_span(&self, offset: usize) -> Span {
+        self.position_at(offset).span
+    }
+
+    // Replays the records up to the offset, positions are only looked up for errors and
+    // traces.
+    fn position_at(&self, offset: usize) -> Position {
+        let mut bytes = self.positions.iter().copied();
+        let mut position = Position::default();
+        while let Some(start) = read_varint(&mut bytes) {
+            let line = unzigzag(read_varint(&mut bytes), position.line);
+            let span_start = unzigzag(read_varint(&mut bytes), position.span.start);
+            let length = read_varint(&mut bytes).expect("position records are written whole");
+            let next = Position {
+                start: position.start + start,
+                line,
+                span: Span {
+                    start: span_start,
+                    end: span_start + length,
+                },
+            };
+            if next.start > offset {
+                break;
+            }
+            position = next;
+        }
+        position
+    }
+
+    // Adds the constant and the instruction loading it, OpConstantLong is used once the
+    // index does not fit in a byte. Returns None if the chunk has too many constants.
+    pub fn write_constant(&mut self, value: Value, line: usize, span: Span) -> Option<usize> {
+        if self.constants.count() >= CONSTANTS_LONG_MAX {
+            return None;
+        }
+        let constant = self.add_constants(value);
+        match u8::try_from(constant) {
+            Ok(short) => {
+                self.write(OpCode::OpConstant.into(), line, span);
+                self.write(short, line, span);
+            }
+            Err(_) => {
+                self.write(OpCode::OpConstantLong.into(), line, span);
+                let [_, high, middle, low] = (constant as u32).to_be_bytes();
+                for byte in [high, middle, low] {
+                    self.write(byte, line, span);
+                }
+            }
+        }
+        Some(constant)
+    }
+
+    pub fn add_constants(&mut self, value: Value) -> usize {
+        self.constants.write(value);
+        self.constants.count() - 1
+    }
+
+    pub fn get_constant(&self, index: usize) -> &Value {
+        self.constants.read_value(index)
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        self.constants.values()
+    }
+}
+
+// The disassembler is only needed for tracing, see the `trace` feature.
+#[cfg(any(feature = "trace", test))]
+impl Chunk {
+    pub fn get_line(&self, offset: usize) -> usize {
+        self.position_at(offset).line
+    }
+
+    pub fn disassemble_chunk(&self, name: &str, out: &mut dyn Write) -> Result<(), LoxError> {
+        writeln!(out, "== {} ==", name)?;
 
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, out)?;
         }
+        Ok(())
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
-        let instruction: OpCode = self.code[offset].into();
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!(" |   ");
+    pub fn disassemble_instruction(
+        &self,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> Result<usize, LoxError> {
+        write!(out, "{:04} ", offset)?;
+        let instruction = OpCode::try_from(self.code[offset])?;
+        let line = self.get_line(offset);
+        if offset > 0 && line == self.get_line(offset - 1) {
+            write!(out, " |   ")?;
         } else {
-            print!("{:4} ", self.lines[offset])
+            write!(out, "{:4} ", line)?;
         }
+        // The prefix is listed together with the instruction it widens.
+        let (instruction, wide) = match instruction {
+            OpCode::OpWide => (OpCode::try_from(self.code[offset + 1])?, true),
+            instruction => (instruction, false),
+        };
         match instruction {
-            OpCode::OpReturn => self.simple_instruction("OP_RETURN", offset, &"\n"),
-            OpCode::OpConstant => self.constant_instruction("OP_CONSTANT", offset),
-            OpCode::OpAdd => self.simple_instruction("OP_ADD", offset, &""),
-            OpCode::OpSubtract => self.simple_instruction("OP_SUBTRACT", offset, &""),
-            OpCode::OpMultiply => self.simple_instruction("OP_MULTIPLY", offset, &""),
-            OpCode::OpDevide => self.simple_instruction("OP_DEVIDED", offset, &""),
-            OpCode::OpNegate => self.simple_instruction("OP_NEGATE", offset, &""),
+            OpCode::OpReturn => self.simple_instruction("OP_RETURN", offset, "\n", out),
+            OpCode::OpConstant => self.constant_instruction("OP_CONSTANT", offset, false, out),
+            OpCode::OpConstantLong => {
+                self.constant_long_instruction("OP_CONSTANT_LONG", offset, out)
+            }
+            OpCode::OpNil => self.simple_instruction("OP_NIL", offset, "", out),
+            OpCode::OpTrue => self.simple_instruction("OP_TRUE", offset, "", out),
+            OpCode::OpFalse => self.simple_instruction("OP_FALSE", offset, "", out),
+            OpCode::OpPop => self.simple_instruction("OP_POP", offset, "", out),
+            OpCode::OpGetLocal => self.byte_instruction("OP_GET_LOCAL", offset, out),
+            OpCode::OpSetLocal => self.byte_instruction("OP_SET_LOCAL", offset, out),
+            OpCode::OpGetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset, wide, out),
+            OpCode::OpDefineGlobal => {
+                self.constant_instruction("OP_DEFINE_GLOBAL", offset, wide, out)
+            }
+            OpCode::OpSetGlobal => self.constant_instruction("OP_SET_GLOBAL", offset, wide, out),
+            OpCode::OpAdd => self.simple_instruction("OP_ADD", offset, "", out),
+            OpCode::OpSubtract => self.simple_instruction("OP_SUBTRACT", offset, "", out),
+            OpCode::OpMultiply => self.simple_instruction("OP_MULTIPLY", offset, "", out),
+            OpCode::OpDevide => self.simple_instruction("OP_DEVIDED", offset, "", out),
+            OpCode::OpNegate => self.simple_instruction("OP_NEGATE", offset, "", out),
+            OpCode::OpNot => self.simple_instruction("OP_NOT", offset, "", out),
+            OpCode::OpEqual => self.simple_instruction("OP_EQUAL", offset, "", out),
+            OpCode::OpGreater => self.simple_instruction("OP_GREATER", offset, "", out),
+            OpCode::OpLess => self.simple_instruction("OP_LESS", offset, "", out),
+            OpCode::OpPrint => self.simple_instruction("OP_PRINT", offset, "", out),
+            OpCode::OpJump => self.jump_instruction("OP_JUMP", 1, offset, out),
+            OpCode::OpJumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE", 1, offset, out),
+            OpCode::OpLoop => self.jump_instruction("OP_LOOP", -1, offset, out),
+            OpCode::OpGetUpvalue => self.byte_instruction("OP_GET_UPVALUE", offset, out),
+            OpCode::OpSetUpvalue => self.byte_instruction("OP_SET_UPVALUE", offset, out),
+            OpCode::OpGetProperty => {
+                self.constant_instruction("OP_GET_PROPERTY", offset, wide, out)
+            }
+            OpCode::OpSetProperty => {
+                self.constant_instruction("OP_SET_PROPERTY", offset, wide, out)
+            }
+            OpCode::OpGetSuper => self.constant_instruction("OP_GET_SUPER", offset, wide, out),
+            OpCode::OpCall => self.byte_instruction("OP_CALL", offset, out),
+            OpCode::OpInvoke => self.invoke_instruction("OP_INVOKE", offset, wide, out),
+            OpCode::OpSuperInvoke => self.invoke_instruction("OP_SUPER_INVOKE", offset, wide, out),
+            OpCode::OpClosure => self.closure_instruction("OP_CLOSURE", offset, wide, out),
+            OpCode::OpCloseUpvalue => self.simple_instruction("OP_CLOSE_UPVALUE", offset, "", out),
+            OpCode::OpClass => self.constant_instruction("OP_CLASS", offset, wide, out),
+            OpCode::OpInherit => self.simple_instruction("OP_INHERIT", offset, "", out),
+            OpCode::OpMethod => self.constant_instruction("OP_METHOD", offset, wide, out),
+            OpCode::OpWide => Err(LoxError::InvalidBytecode(OpCode::OpWide.into())),
         }
     }
-    fn simple_instruction(&self, name: &str, offset: usize, end: &str) -> usize {
-        println!("{}{}", name, end);
-        offset + 1
+
+    fn simple_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        end: &str,
+        out: &mut dyn Write,
+    ) -> Result<usize, LoxError> {
+        writeln!(out, "{}{}", name, end)?;
+        Ok(offset + 1)
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant = self.code[offset + 1];
-        print!("{:-16} {:4} '", name, constant);
-        self.print_value(self.get_constant(constant as usize));
-        print!("'\n");
-        offset + 2
+    fn byte_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> Result<usize, LoxError> {
+        let slot = self.code[offset + 1];
+        writeln!(out, "{:-16} {:4}", name, slot)?;
+        Ok(offset + 2)
     }
 
-    pub fn add_constants(&mut self, value: Value) -> usize {
-        self.constants.write(value);
-        self.constants.count() - 1
+    fn jump_instruction(
+        &self,
+        name: &str,
+        sign: isize,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> Result<usize, LoxError> {
+        let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+        let target = (offset + 3).wrapping_add_signed(sign * jump as isize);
+        writeln!(out, "{:-16} {:4} -> {}", name, offset, target)?;
+        Ok(offset + 3)
     }
 
-    pub fn print_value(&self, value: &Value) {
-        print!("{}", *value)
+    // The constant index following the instruction at `offset` and the offset after it.
+    fn constant_operand(&self, offset: usize, wide: bool) -> (usize, usize) {
+        if wide {
+            (read_u24(&self.code[offset + 2..offset + 5]), offset + 5)
+        } else {
+            (self.code[offset + 1] as usize, offset + 2)
+        }
     }
 
-    pub fn get_constant(&self, index: usize) -> &Value {
-        self.constants.read_value(index)
+    fn constant_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        wide: bool,
+        out: &mut dyn Write,
+    ) -> Result<usize, LoxError> {
+        let (constant, offset) = self.constant_operand(offset, wide);
+        let value = self.get_constant(constant);
+        writeln!(out, "{:-16} {:4} '{}'", name, constant, value)?;
+        Ok(offset)
+    }
+
+    fn constant_long_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> Result<usize, LoxError> {
+        let constant = read_u24(&self.code[offset + 1..offset + 4]);
+        let value = self.get_constant(constant);
+        writeln!(out, "{:-16} {:4} '{}'", name, constant, value)?;
+        Ok(offset + 4)
+    }
+
+    fn invoke_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        wide: bool,
+        out: &mut dyn Write,
+    ) -> Result<usize, LoxError> {
+        let (constant, offset) = self.constant_operand(offset, wide);
+        let arg_count = self.code[offset];
+        let value = self.get_constant(constant);
+        writeln!(
+            out,
+            "{:-16} ({} args) {:4} '{}'",
+            name, arg_count, constant, value
+        )?;
+        Ok(offset + 1)
+    }
+
+    fn closure_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        wide: bool,
+        out: &mut dyn Write,
+    ) -> Result<usize, LoxError> {
+        let (constant, mut offset) = self.constant_operand(offset, wide);
+        let function = self.get_constant(constant);
+        writeln!(out, "{:-16} {:4} {}", name, constant, function)?;
+
+        let Some(function) = function.as_obj() else {
+            unreachable!("closures are only created from function constants")
+        };
+        for _ in 0..function.as_function().upvalue_count {
+            let is_local = self.code[offset];
+            let index = self.code[offset + 1];
+            let kind = if is_local == 1 { "local" } else { "upvalue" };
+            writeln!(
+                out,
+                "{:04}    |                     {} {}",
+                offset, kind, index
+            )?;
+            offset += 2;
+        }
+        Ok(offset)
     }
 }
 
 // Values
 //
 
-pub type Value = f64;
+// Which representation is compiled in depends on the `nan-boxing` feature, code outside
+// of this section only goes through the constructors and accessors.
+
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone, Copy, PartialEq)]
+enum Repr {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone, Copy, PartialEq)]
+pub struct Value(Repr);
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub fn nil() -> Self {
+        Value(Repr::Nil)
+    }
+
+    pub fn bool(b: bool) -> Self {
+        Value(Repr::Bool(b))
+    }
+
+    pub fn number(n: f64) -> Self {
+        Value(Repr::Number(n))
+    }
+
+    pub fn obj(obj: ObjRef) -> Self {
+        Value(Repr::Obj(obj))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self.0, Repr::Nil)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0 {
+            Repr::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self.0 {
+            Repr::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<ObjRef> {
+        match self.0 {
+            Repr::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const TAG_NIL: u64 = 1;
+#[cfg(feature = "nan-boxing")]
+const TAG_FALSE: u64 = 2;
+#[cfg(feature = "nan-boxing")]
+const TAG_TRUE: u64 = 3;
+
+// Numbers are stored as their own bits, every other value is a quiet NaN that no
+// arithmetic produces. Singletons are tagged in the low bits, objects set the sign bit
+// and keep their pointer in the low 48 bits.
+#[cfg(feature = "nan-boxing")]
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    pub fn nil() -> Self {
+        Value(QNAN | TAG_NIL)
+    }
+
+    pub fn bool(b: bool) -> Self {
+        Value(QNAN | if b { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    pub fn number(n: f64) -> Self {
+        Value(n.to_bits())
+    }
+
+    pub fn obj(obj: ObjRef) -> Self {
+        let ptr = obj.0.as_ptr() as u64;
+        debug_assert_eq!(ptr & (SIGN_BIT | QNAN), 0, "pointers must fit in 48 bits");
+        Value(SIGN_BIT | QNAN | ptr)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == QNAN | TAG_NIL
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        // true and false only differ in the lowest bit.
+        if self.0 | 1 == QNAN | TAG_TRUE {
+            Some(self.0 == QNAN | TAG_TRUE)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        if self.0 & QNAN != QNAN {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<ObjRef> {
+        if self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN {
+            NonNull::new((self.0 & !(SIGN_BIT | QNAN)) as *mut Obj).map(ObjRef)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        // Compared as floats so NaN is not equal to itself, like the enum representation.
+        match (self.as_number(), other.as_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+impl Value {
+    // nil and false are falsey, every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || self.as_bool() == Some(false)
+    }
+
+    pub fn is_string(&self) -> bool {
+        self.as_obj()
+            .is_some_and(|obj| matches!(obj.kind, ObjKind::String(_)))
+    }
+
+    pub fn is_instance(&self) -> bool {
+        self.as_obj()
+            .is_some_and(|obj| matches!(obj.kind, ObjKind::Instance(_)))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(b) = self.as_bool() {
+            write!(f, "{}", b)
+        } else if let Some(n) = self.as_number() {
+            write!(f, "{}", n)
+        } else if let Some(obj) = self.as_obj() {
+            write!(f, "{}", obj)
+        } else {
+            write!(f, "nil")
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(b) = self.as_bool() {
+            write!(f, "Bool({})", b)
+        } else if let Some(n) = self.as_number() {
+            write!(f, "Number({:?})", n)
+        } else if let Some(obj) = self.as_obj() {
+            write!(f, "Obj({:?})", obj)
+        } else {
+            write!(f, "Nil")
+        }
+    }
+}
 
 pub struct ValueArray {
     values: Vec<Value>,
@@ -136,18 +695,405 @@ impl ValueArray {
     pub fn count(&self) -> usize {
         self.values.len()
     }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+}
+
+// Objects
+//
+
+pub struct Obj {
+    pub kind: ObjKind,
+    // Set while the garbage collector traces reachable objects.
+    pub is_marked: bool,
+}
+
+pub enum ObjKind {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+}
+
+pub struct ObjString {
+    pub chars: String,
+}
+
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // None for the top level script.
+    pub name: Option<ObjRef>,
+    // The code the function was compiled from, runtime errors quote it.
+    pub source: Rc<str>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjRef>, source: Rc<str>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+            source,
+        }
+    }
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
 }
 
+// A function together with the variables it captured, every function is wrapped in a
+// closure at runtime.
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+// A captured variable, it points into the stack while the variable is in scope and
+// takes over the value once the variable goes out of scope.
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct ObjClass {
+    pub name: ObjRef,
+    // Method closures keyed by their interned name.
+    pub methods: HashMap<ObjRef, Value>,
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+// A method closure bound to the instance it was accessed on.
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// A pointer to an object owned by `memory::Heap`, copying it does not copy the object.
+// Strings are interned so two string references are equal only if they point to the
+// same object.
+#[derive(Clone, Copy)]
+pub struct ObjRef(NonNull<Obj>);
+
+impl ObjRef {
+    pub(crate) fn from_box(obj: Box<Obj>) -> Self {
+        ObjRef(NonNull::from(Box::leak(obj)))
+    }
+
+    // The caller must guarantee no other reference to the object is used afterwards.
+    pub(crate) unsafe fn free(self) {
+        drop(Box::from_raw(self.0.as_ptr()));
+    }
+
+    pub fn as_string(&self) -> &ObjString {
+        match &self.kind {
+            ObjKind::String(string) => string,
+            _ => panic!("object is not a string"),
+        }
+    }
+
+    pub fn as_function(&self) -> &ObjFunction {
+        match &self.kind {
+            ObjKind::Function(function) => function,
+            _ => panic!("object is not a function"),
+        }
+    }
+
+    pub fn as_closure(&self) -> &ObjClosure {
+        match &self.kind {
+            ObjKind::Closure(closure) => closure,
+            _ => panic!("object is not a closure"),
+        }
+    }
+
+    pub fn as_upvalue(&self) -> &ObjUpvalue {
+        match &self.kind {
+            ObjKind::Upvalue(upvalue) => upvalue,
+            _ => panic!("object is not an upvalue"),
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self) -> &mut ObjUpvalue {
+        match &mut self.kind {
+            ObjKind::Upvalue(upvalue) => upvalue,
+            _ => panic!("object is not an upvalue"),
+        }
+    }
+
+    pub fn as_class(&self) -> &ObjClass {
+        match &self.kind {
+            ObjKind::Class(class) => class,
+            _ => panic!("object is not a class"),
+        }
+    }
+
+    pub fn as_class_mut(&mut self) -> &mut ObjClass {
+        match &mut self.kind {
+            ObjKind::Class(class) => class,
+            _ => panic!("object is not a class"),
+        }
+    }
+
+    pub fn as_instance(&self) -> &ObjInstance {
+        match &self.kind {
+            ObjKind::Instance(instance) => instance,
+            _ => panic!("object is not an instance"),
+        }
+    }
+
+    pub fn as_instance_mut(&mut self) -> &mut ObjInstance {
+        match &mut self.kind {
+            ObjKind::Instance(instance) => instance,
+            _ => panic!("object is not an instance"),
+        }
+    }
+}
+
+impl Deref for ObjRef {
+    type Target = Obj;
+
+    fn deref(&self) -> &Obj {
+        // The heap keeps every object alive for as long as it is referenced.
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl DerefMut for ObjRef {
+    fn deref_mut(&mut self) -> &mut Obj {
+        unsafe { self.0.as_mut() }
+    }
+}
+
+impl PartialEq for ObjRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for ObjRef {}
+
+// Hashes the address, interned strings can be used directly as table keys.
+impl Hash for ObjRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Display for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ObjKind::String(string) => write!(f, "{}", string.chars),
+            ObjKind::Function(function) => write!(f, "{}", function),
+            ObjKind::Closure(closure) => write!(f, "{}", closure.function),
+            ObjKind::Upvalue(_) => write!(f, "upvalue"),
+            ObjKind::Class(class) => write!(f, "{}", class.name),
+            ObjKind::Instance(instance) => {
+                write!(f, "{} instance", instance.class.as_class().name)
+            }
+            ObjKind::BoundMethod(bound) => write!(f, "{}", bound.method),
+        }
+    }
+}
+
+impl fmt::Debug for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ObjKind::String(string) => write!(f, "{:?}", string.chars),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+#[cfg(test)]
 mod test_chunks {
-    use crate::common::{Chunk, OpCode};
+    use crate::common::{read_u24, Chunk, OpCode, Value, OPCODES};
+    use crate::compiler::Compiler;
+    use crate::errors::LoxError;
+    use crate::memory::Heap;
+    use crate::scanner::Span;
 
     #[test]
     fn test_chunks() {
         let mut chunk = Chunk::new();
-        let constant = chunk.add_constants(1.2);
-        chunk.write(OpCode::OpConstant.into(), 123);
-        chunk.write(constant as u8, 123);
-        chunk.write(OpCode::OpReturn.into(), 123);
-        chunk.disassemble_chunk("test")
+        let constant = chunk.add_constants(Value::number(1.2));
+        chunk.write(OpCode::OpConstant.into(), 123, Span::default());
+        chunk.write(constant as u8, 123, Span::default());
+        chunk.write(OpCode::OpReturn.into(), 123, Span::default());
+        let mut out = Vec::new();
+        chunk.disassemble_chunk("test", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "== test ==\n0000  123 OP_CONSTANT         0 '1.2'\n0002  |   OP_RETURN\n\n"
+        );
+    }
+
+    #[test]
+    fn test_opcode_try_from() {
+        for op in OPCODES {
+            let byte = u8::from(op);
+            assert_eq!(OpCode::try_from(byte).map(u8::from).ok(), Some(byte));
+        }
+        assert!(matches!(OpCode::try_from(0), Ok(OpCode::OpConstant)));
+        assert!(matches!(
+            OpCode::try_from(OpCode::OpWide as u8),
+            Ok(OpCode::OpWide)
+        ));
+        assert!(matches!(
+            OpCode::try_from(OpCode::OpWide as u8 + 1),
+            Err(LoxError::InvalidBytecode(39))
+        ));
+    }
+
+    #[test]
+    fn test_line_runs() {
+        let mut chunk = Chunk::new();
+        let runs = [(1, 0, 3, 3), (1, 5, 6, 2), (4, 2, 4, 1), (7, 40, 41, 4)];
+        for (line, start, end, count) in runs {
+            for _ in 0..count {
+                chunk.write(OpCode::OpNil.into(), line, Span { start, end });
+            }
+        }
+        // One record of four bytes per position.
+        assert_eq!(chunk.positions.len(), 4 * 4);
+        let positions: Vec<_> = (0..chunk.code.len())
+            .map(|offset| {
+                let span = chunk.get_span(offset);
+                (chunk.get_line(offset), span.start, span.end)
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                (1, 0, 3),
+                (1, 0, 3),
+                (1, 0, 3),
+                (1, 5, 6),
+                (1, 5, 6),
+                (4, 2, 4),
+                (7, 40, 41),
+                (7, 40, 41),
+                (7, 40, 41),
+                (7, 40, 41)
+            ]
+        );
+
+        // Large steps take more than one byte per varint.
+        let span = Span {
+            start: 1000,
+            end: 1300,
+        };
+        chunk.write(OpCode::OpNil.into(), 2, span);
+        chunk.write(OpCode::OpNil.into(), 300, Span { start: 0, end: 1 });
+        assert_eq!(chunk.get_line(10), 2);
+        assert_eq!(chunk.get_span(10), span);
+        assert_eq!(chunk.get_line(11), 300);
+        assert_eq!(chunk.get_span(11), Span { start: 0, end: 1 });
+    }
+
+    #[test]
+    fn test_line_table_size() {
+        let source = "class Counter {
+              init() { this.count = 0; }
+              add(n) { this.count = this.count + n; return this; }
+            }
+            fun sum(limit) {
+              var counter = Counter();
+              for (var i = 0; i < limit; i = i + 1) {
+                if (i > 2 and i != 5) counter.add(i);
+              }
+              return counter.count;
+            }
+            print sum(10);";
+        let mut heap = Heap::default();
+        let script = Compiler::new(source, &mut heap, &|_| {}).compile().unwrap();
+        let chunk = &script.as_function().chunk;
+        let sum = chunk
+            .constants()
+            .iter()
+            .filter_map(|constant| constant.as_obj())
+            .find(|constant| constant.to_string() == "<fn sum>")
+            .unwrap();
+        for chunk in [chunk, &sum.as_function().chunk] {
+            // Well under the four bytes per byte of code of storing a u32 column each.
+            assert!(chunk.positions.len() < 2 * chunk.code.len());
+        }
+    }
+
+    #[test]
+    fn test_write_constant() {
+        let mut chunk = Chunk::new();
+        for i in 0..=256 {
+            assert_eq!(
+                chunk.write_constant(Value::number(i as f64), 1, Span::default()),
+                Some(i)
+            );
+        }
+        assert_eq!(chunk.code.len(), 256 * 2 + 4);
+        assert_eq!(chunk.code[510], OpCode::OpConstant as u8);
+        assert_eq!(chunk.code[511], 255);
+        assert_eq!(chunk.code[512..], [OpCode::OpConstantLong as u8, 0, 1, 0]);
+        assert_eq!(
+            *chunk.get_constant(read_u24(&chunk.code[513..])),
+            Value::number(256.0)
+        );
+        let mut out = Vec::new();
+        assert_eq!(chunk.disassemble_instruction(512, &mut out).unwrap(), 516);
+        assert_eq!(out, b"0512  |   OP_CONSTANT_LONG  256 '256'\n");
+    }
+
+    #[test]
+    fn test_values() {
+        assert!(Value::nil().is_falsey());
+        assert!(Value::bool(false).is_falsey());
+        assert!(!Value::bool(true).is_falsey());
+        assert!(!Value::number(0.0).is_falsey());
+        assert_eq!(Value::number(1.0), Value::number(1.0));
+        assert_ne!(Value::number(f64::NAN), Value::number(f64::NAN));
+        assert_ne!(Value::nil(), Value::bool(false));
+        assert_ne!(Value::number(0.0), Value::bool(false));
+        assert_eq!(Value::number(7.0).to_string(), "7");
+        assert_eq!(Value::number(-2.5).to_string(), "-2.5");
+        assert_eq!(Value::nil().to_string(), "nil");
+        assert_eq!(Value::bool(true).to_string(), "true");
+        assert_eq!(Value::bool(false).as_bool(), Some(false));
+        assert_eq!(Value::nil().as_number(), None);
+        assert_eq!(
+            Value::number(f64::INFINITY).as_number(),
+            Some(f64::INFINITY)
+        );
+        assert!(Value::number(f64::NAN).as_number().unwrap().is_nan());
+        #[cfg(feature = "nan-boxing")]
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn test_string_values() {
+        let mut heap = Heap::default();
+        let a = Value::obj(heap.copy_string("lox"));
+        let b = Value::obj(heap.take_string("lox".to_string()));
+        let c = Value::obj(heap.copy_string("rust"));
+        assert!(a.is_string());
+        assert!(!Value::nil().is_string());
+        assert_eq!(a.as_obj(), b.as_obj());
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(a.to_string(), "lox");
     }
 }
@@ -1,153 +1,1736 @@
-use crate::scanner::{Scanner, Token, TokenType};
+use crate::common::{Chunk, ObjFunction, ObjRef, OpCode, Value, CONSTANTS_LONG_MAX};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::rc::Rc;
 
-struct Parser {
-    current: Option<Token>,
-    previous: Option<Token>,
+use crate::diagnostics::Diagnostic;
+use crate::errors::LoxError;
+use crate::memory::Heap;
+use crate::scanner::{Scanner, Span, Token, TokenType};
+
+struct Parser<'src> {
+    current: Option<Token<'src>>,
+    previous: Option<Token<'src>>,
 
-    had_error: bool,
     panic_mode: bool,
 }
 
-impl Parser {
+impl Parser<'_> {
     pub fn is_current_err(&self) -> bool {
-        false
+        self.current.as_ref().is_some_and(|tok| tok.is_err())
     }
 }
 
-struct Compiler {
-    parser: Parser,
-    scanner: Scanner,
+// Precedence levels from lowest to highest, the order of the variants matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
 }
 
-impl Compiler {
-    pub fn new(source: &str) -> Self {
+impl Precedence {
+    fn next(self) -> Self {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call => Precedence::Primary,
+            Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
         Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+fn get_rule<'a>(type_: TokenType) -> ParseRule<'a> {
+    match type_ {
+        TokenType::LeftParen => ParseRule::new(
+            Some(Compiler::grouping),
+            Some(Compiler::call),
+            Precedence::Call,
+        ),
+        TokenType::Dot => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
+        TokenType::Super => ParseRule::new(Some(Compiler::super_), None, Precedence::None),
+        TokenType::This => ParseRule::new(Some(Compiler::this_), None, Precedence::None),
+        TokenType::Minus => ParseRule::new(
+            Some(Compiler::unary),
+            Some(Compiler::binary),
+            Precedence::Term,
+        ),
+        TokenType::Plus => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
+        TokenType::Slash => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        TokenType::Star => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        TokenType::Bang => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+        TokenType::BangEqual | TokenType::EqualEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Equality)
+        }
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison)
+        }
+        TokenType::And => ParseRule::new(None, Some(Compiler::and_), Precedence::And),
+        TokenType::Or => ParseRule::new(None, Some(Compiler::or_), Precedence::Or),
+        TokenType::Identifier => ParseRule::new(Some(Compiler::variable), None, Precedence::None),
+        TokenType::String => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+        TokenType::Number => ParseRule::new(Some(Compiler::number), None, Precedence::None),
+        TokenType::False | TokenType::Nil | TokenType::True => {
+            ParseRule::new(Some(Compiler::literal), None, Precedence::None)
+        }
+        _ => ParseRule::new(None, None, Precedence::None),
+    }
+}
+
+// Maximum number of locals in scope at once, a slot index must fit in one byte.
+const LOCALS_MAX: usize = u8::MAX as usize + 1;
+
+// Maximum number of variables a closure can capture, an upvalue index is one byte.
+const UPVALUES_MAX: usize = u8::MAX as usize + 1;
+
+// The argument count of a call is a single byte operand.
+const PARAMETERS_MAX: usize = u8::MAX as usize;
+
+// How many errors are reported before compiling stops, unless configured otherwise.
+pub const MAX_ERRORS: NonZeroUsize = NonZeroUsize::new(20).unwrap();
+
+struct Local {
+    name: String,
+    // Where the variable was declared, None for the slots the compiler reserves.
+    span: Option<Span>,
+    // None until the initializer has been compiled.
+    depth: Option<usize>,
+    // Captured locals are moved to the heap when they go out of scope.
+    is_captured: bool,
+}
+
+// A variable captured by a closure, either a local of the enclosing function or one of
+// its upvalues.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+// The innermost enclosing loop, break and continue statements jump relative to it.
+struct Loop {
+    // Where continue jumps to, the increment clause for a for loop.
+    start: usize,
+    // Locals deeper than this are popped when jumping out of the loop body.
+    scope_depth: usize,
+    // Break jumps, patched once the end of the loop is known.
+    break_jumps: Vec<usize>,
+}
+
+// A class declaration enclosing the code being compiled.
+struct ClassState {
+    // `super` is only valid inside classes that inherit from another one.
+    has_superclass: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// Everything tracked while compiling the body of one function, the top level script is
+// compiled as a function too.
+struct FunctionState {
+    function: ObjFunction,
+    type_: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    // Constant indices of the names used in the function, each name is added once.
+    identifiers: HashMap<ObjRef, usize>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+}
+
+impl FunctionState {
+    fn new(type_: FunctionType, name: Option<ObjRef>, source: Rc<str>) -> Self {
+        Self {
+            function: ObjFunction::new(name, source),
+            type_,
+            // The first slot holds the function being called, or the receiver for methods.
+            locals: vec![Local {
+                name: match type_ {
+                    FunctionType::Initializer | FunctionType::Method => "this".to_string(),
+                    FunctionType::Function | FunctionType::Script => String::new(),
+                },
+                span: None,
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            identifiers: HashMap::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+        }
+    }
+}
+
+pub struct Compiler<'a> {
+    // Shared by every function compiled from it.
+    source: Rc<str>,
+    parser: Parser<'a>,
+    scanner: Scanner<'a>,
+    // One entry per function being compiled, the innermost function is last.
+    states: Vec<FunctionState>,
+    // Class declarations enclosing the code being compiled, the innermost one is last.
+    classes: Vec<ClassState>,
+    // String constants and functions are allocated on the vm heap.
+    heap: &'a mut Heap,
+    // Marks the objects the owner of the heap still uses before a collection.
+    vm_roots: &'a dyn Fn(&mut Heap),
+    // Scan and compile errors in the order they were found.
+    errors: Vec<LoxError>,
+    // Compiling stops once this many errors were reported.
+    max_errors: NonZeroUsize,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(source: &'a str, heap: &'a mut Heap, vm_roots: &'a dyn Fn(&mut Heap)) -> Self {
+        let shared: Rc<str> = source.into();
+        Self {
+            source: shared.clone(),
             parser: Parser {
                 current: None,
                 previous: None,
-                had_error: false,
                 panic_mode: false,
             },
             scanner: Scanner::new(source),
+            states: vec![FunctionState::new(FunctionType::Script, None, shared)],
+            classes: Vec::new(),
+            heap,
+            vm_roots,
+            errors: Vec::new(),
+            max_errors: MAX_ERRORS,
+        }
+    }
+
+    pub fn max_errors(mut self, max_errors: NonZeroUsize) -> Self {
+        self.max_errors = max_errors;
+        self
+    }
+
+    // Compiles a whole program into the top level function, or returns every error that
+    // was reported.
+    pub fn compile(mut self) -> Result<ObjRef, Vec<LoxError>> {
+        self.advance();
+        while !self.match_(TokenType::Eof) {
+            self.declaration();
+            // Checked after compiling, so giving up always leaves an error to return.
+            if self.errors.len() >= self.max_errors.get() {
+                // Only mention the cap if it left part of the source unchecked.
+                if !self.check(TokenType::Eof) {
+                    let note = match self.max_errors.get() {
+                        1 => "stopped after 1 error".to_string(),
+                        max_errors => format!("stopped after {} errors", max_errors),
+                    };
+                    if let Some(LoxError::Scan(last) | LoxError::Compile(last)) =
+                        self.errors.last_mut()
+                    {
+                        last.notes.push(note);
+                    }
+                }
+                break;
+            }
         }
+        let (function, _) = self.end_compiler();
+
+        if self.errors.is_empty() {
+            Ok(function)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.states
+            .last()
+            .expect("the script state is never popped")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states
+            .last_mut()
+            .expect("the script state is never popped")
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    fn previous(&self) -> &Token<'a> {
+        self.parser
+            .previous
+            .as_ref()
+            .expect("previous token is set after the first advance")
+    }
+
+    fn previous_lexeme(&self) -> &'a str {
+        self.previous().lexeme
+    }
+
+    fn current(&self) -> &Token<'a> {
+        self.parser
+            .current
+            .as_ref()
+            .expect("current token is set after the first advance")
     }
 
     fn advance(&mut self) {
         self.parser.previous = self.parser.current.take();
         loop {
-            let tok = self.scanner.scan_token();
+            self.parser.current = Some(self.scanner.scan_token());
+
+            if !self.parser.is_current_err() {
+                break;
+            }
+
+            let diagnostic = self
+                .current()
+                .diagnostic()
+                .expect("error tokens carry a diagnostic");
+            self.report(LoxError::Scan(diagnostic))
+        }
+    }
 
-            // it feels like you either extract all data upfront
-            // or you clone the data
+    fn check(&self, type_: TokenType) -> bool {
+        self.current().type_ == type_
+    }
 
-            let is_err = tok.is_err();
-            let msg = tok.message();
+    fn match_(&mut self, type_: TokenType) -> bool {
+        if !self.check(type_) {
+            return false;
+        }
+        self.advance();
+        true
+    }
 
-            self.parser.current = Some(tok);
+    fn consume(&mut self, type_: TokenType, message: &str) {
+        if self.current().type_ == type_ {
+            self.advance();
+            return;
+        }
 
-            if !is_err {
-                break;
+        self.error_at_current("E0100", message);
+    }
+
+    // Emitting bytecode
+
+    // The line and span of the last consumed token, where code is credited to by default.
+    fn previous_position(&self) -> (usize, Span) {
+        (self.previous().line, self.previous().span)
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let position = self.previous_position();
+        self.emit_byte_at(byte, position);
+    }
+
+    fn emit_byte_at(&mut self, byte: u8, token: (usize, Span)) {
+        let (line, span) = token;
+        self.current_chunk().write(byte, line, span);
+    }
+
+    // Attributes the instructions of an operator to the operator token instead of the
+    // last token of its operands, so runtime errors point at the operator.
+    fn emit_ops_at(&mut self, ops: &[OpCode], token: (usize, Span)) {
+        for op in ops {
+            self.emit_byte_at((*op).into(), token);
+        }
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        self.emit_byte(op.into());
+    }
+
+    // Emits an instruction with a one byte operand, larger operands get the OpWide prefix
+    // and 24 bits. Only constant indices grow past a byte.
+    fn emit_with_operand(&mut self, op: OpCode, operand: usize) {
+        let position = self.previous_position();
+        self.emit_with_operand_at(op, operand, position);
+    }
+
+    // Instructions that fail on a name are credited to the name token, wherever the code
+    // around it ends.
+    fn emit_with_operand_at(&mut self, op: OpCode, operand: usize, token: (usize, Span)) {
+        let bytes = match u8::try_from(operand) {
+            Ok(operand) => vec![op.into(), operand],
+            Err(_) => {
+                let [_, high, middle, low] = (operand as u32).to_be_bytes();
+                vec![OpCode::OpWide.into(), op.into(), high, middle, low]
+            }
+        };
+        for byte in bytes {
+            self.emit_byte_at(byte, token);
+        }
+    }
+
+    // Emits a jump with a placeholder operand, returns the offset to patch it later.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_op(instruction);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.current_chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().code.len() - offset - 2;
+
+        let Ok(jump) = u16::try_from(jump) else {
+            self.error("E0103", "Too much code to jump over.");
+            return;
+        };
+
+        let [high, low] = jump.to_be_bytes();
+        self.current_chunk().code[offset] = high;
+        self.current_chunk().code[offset + 1] = low;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::OpLoop);
+
+        // +2 to jump over the operand of the loop instruction too.
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        let Ok(offset) = u16::try_from(offset) else {
+            self.error("E0103", "Loop body too large.");
+            return;
+        };
+
+        let [high, low] = offset.to_be_bytes();
+        self.emit_bytes(high, low);
+    }
+
+    fn emit_return(&mut self) {
+        // An initializer always returns the new instance.
+        if self.state().type_ == FunctionType::Initializer {
+            self.emit_bytes(OpCode::OpGetLocal.into(), 0);
+        } else {
+            self.emit_op(OpCode::OpNil);
+        }
+        self.emit_op(OpCode::OpReturn);
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.current_chunk().add_constants(value);
+        if constant >= CONSTANTS_LONG_MAX {
+            self.error("E0103", "Too many constants in one chunk.");
+            return 0;
+        }
+        constant
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let (line, span) = self.previous_position();
+        if self
+            .current_chunk()
+            .write_constant(value, line, span)
+            .is_none()
+        {
+            self.error("E0103", "Too many constants in one chunk.");
+        }
+    }
+
+    // Finishes the innermost function and moves it to the heap, returns it along with
+    // the variables it captures.
+    fn end_compiler(&mut self) -> (ObjRef, Vec<Upvalue>) {
+        self.emit_return();
+        let state = self
+            .states
+            .pop()
+            .expect("end_compiler is paired with a state");
+        (self.heap.alloc_function(state.function), state.upvalues)
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        while self.state().locals.last().is_some_and(|local| {
+            local
+                .depth
+                .is_some_and(|depth| depth > self.state().scope_depth)
+        }) {
+            let local = self.state_mut().locals.pop().expect("checked by the loop");
+            if local.is_captured {
+                self.emit_op(OpCode::OpCloseUpvalue);
+            } else {
+                self.emit_op(OpCode::OpPop);
+            }
+        }
+    }
+
+    // Declarations and statements
+
+    // Everything allocated so far is referenced from the functions being compiled, which
+    // makes the start of a declaration a safe point for collecting garbage.
+    fn collect_garbage(&mut self) {
+        let (states, vm_roots) = (&self.states, self.vm_roots);
+        self.heap.collect_garbage(&|heap| {
+            for state in states {
+                if let Some(name) = state.function.name {
+                    heap.mark_object(name);
+                }
+                for constant in state.function.chunk.constants() {
+                    heap.mark_value(*constant);
+                }
+            }
+            vm_roots(heap);
+        });
+    }
+
+    fn declaration(&mut self) {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        if self.match_(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.parser.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    // Skips tokens until the start of the next statement, so errors after it are reported
+    // on their own.
+    fn synchronize(&mut self) {
+        self.parser.panic_mode = false;
+
+        while !self.check(TokenType::Eof) {
+            if self
+                .parser
+                .previous
+                .is_some_and(|tok| tok.type_ == TokenType::Semicolon)
+            {
+                return;
+            }
+            match self.current().type_ {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous_lexeme();
+        let class_span = self.previous().span;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_with_operand(OpCode::OpClass, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.match_(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+            if class_name == self.previous_lexeme() {
+                let diagnostic = Diagnostic::error("E0111", "A class can't inherit from itself.")
+                    .with_primary(self.previous().span, "")
+                    .with_label(class_span, "class declared here");
+                self.report(LoxError::Compile(diagnostic));
+            }
+
+            // The superclass lives in a local named `super` that methods capture.
+            self.begin_scope();
+            self.add_local("super".to_string(), None);
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_op(OpCode::OpInherit);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // Load the class back so methods can be attached to it.
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_op(OpCode::OpPop);
+
+        if self.classes.pop().is_some_and(|class| class.has_superclass) {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous_lexeme();
+        let constant = self.identifier_constant(name);
+
+        let type_ = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(type_);
+        self.emit_with_operand(OpCode::OpMethod, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function can refer to itself in its body, so it is usable right away.
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, type_: FunctionType) {
+        let name = self.heap.copy_string(self.previous_lexeme());
+        let source = self.source.clone();
+        self.states
+            .push(FunctionState::new(type_, Some(name), source));
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.state_mut().function.arity += 1;
+                if self.state().function.arity > PARAMETERS_MAX {
+                    self.error_at_current("E0103", "Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // No end_scope, the frame is discarded as a whole when the function returns.
+        let (function, upvalues) = self.end_compiler();
+        let constant = self.make_constant(Value::obj(function));
+        self.emit_with_operand(OpCode::OpClosure, constant);
+
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_op(OpCode::OpNil);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> usize {
+        self.consume(TokenType::Identifier, error_message);
+
+        self.declare_variable();
+        // Locals live on the stack, they don't need a name in the constant table.
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(self.previous_lexeme())
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let string = self.heap.copy_string(name);
+        // Strings are interned, equal names are the same object.
+        if let Some(&constant) = self.state().identifiers.get(&string) {
+            return constant;
+        }
+        let constant = self.make_constant(Value::obj(string));
+        self.state_mut().identifiers.insert(string, constant);
+        constant
+    }
+
+    fn declare_variable(&mut self) {
+        if self.state().scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous_lexeme();
+        let span = self.previous().span;
+        let earlier = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| {
+                local
+                    .depth
+                    .is_none_or(|depth| depth >= self.state().scope_depth)
+            })
+            .find(|local| local.name == name)
+            .map(|local| local.span);
+        if let Some(earlier) = earlier {
+            let diagnostic =
+                Diagnostic::error("E0104", "Already a variable with this name in this scope.")
+                    .with_primary(span, "declared again here");
+            let diagnostic = match earlier {
+                Some(earlier) => diagnostic.with_label(earlier, "first declared here"),
+                None => diagnostic,
+            };
+            self.report(LoxError::Compile(diagnostic));
+        }
+
+        self.add_local(name.to_string(), Some(span));
+    }
+
+    fn add_local(&mut self, name: String, span: Option<Span>) {
+        if self.state().locals.len() == LOCALS_MAX {
+            self.error("E0103", "Too many local variables in function.");
+            return;
+        }
+        self.state_mut().locals.push(Local {
+            name,
+            span,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope_depth = self.state().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = self.state_mut().locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_with_operand(OpCode::OpDefineGlobal, global);
+    }
+
+    // Looks the name up in the locals of the function compiled by `self.states[state]`.
+    fn resolve_local(&mut self, state: usize, name: &str) -> Option<u8> {
+        let (slot, local) = self.states[state]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)?;
+        if local.depth.is_none() {
+            self.error("E0105", "Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    // Looks the name up in the enclosing functions, capturing it in every function in
+    // between.
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+
+        if let Some(local) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(state - 1, name)?;
+        Some(self.add_upvalue(state, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.states[state].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+
+        if upvalues.len() == UPVALUES_MAX {
+            self.error("E0103", "Too many closure variables in function.");
+            return 0;
+        }
+
+        let state = &mut self.states[state];
+        state.upvalues.push(upvalue);
+        state.function.upvalue_count = state.upvalues.len();
+        (state.upvalues.len() - 1) as u8
+    }
+
+    fn statement(&mut self) {
+        if self.match_(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_(TokenType::For) {
+            self.for_statement();
+        } else if self.match_(TokenType::If) {
+            self.if_statement();
+        } else if self.match_(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_(TokenType::While) {
+            self.while_statement();
+        } else if self.match_(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.match_(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        self.emit_op(OpCode::OpPop);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::OpJump);
+
+        self.patch_jump(then_jump);
+        self.emit_op(OpCode::OpPop);
+
+        if self.match_(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().type_ == FunctionType::Script {
+            self.error("E0106", "Can't return from top-level code.");
+        }
+
+        if self.match_(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().type_ == FunctionType::Initializer {
+                self.error("E0107", "Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_op(OpCode::OpReturn);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        self.emit_op(OpCode::OpPop);
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(OpCode::OpPop);
+        self.end_loop();
+    }
+
+    // A for loop is compiled as a while loop, the increment clause is placed before the
+    // body in the bytecode so the body jumps back to it after each iteration.
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.match_(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.match_(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(OpCode::OpJumpIfFalse));
+            self.emit_op(OpCode::OpPop);
+        }
+
+        if !self.match_(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::OpJump);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_op(OpCode::OpPop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_op(OpCode::OpPop);
+        }
+        self.end_loop();
+
+        self.end_scope();
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = self.state().scope_depth;
+        self.state_mut().loops.push(Loop {
+            start,
+            scope_depth,
+            break_jumps: Vec::new(),
+        });
+    }
+
+    // Must be called right after the loop, breaks land on the next instruction.
+    fn end_loop(&mut self) {
+        let innermost = self
+            .state_mut()
+            .loops
+            .pop()
+            .expect("end_loop is paired with begin_loop");
+        for jump in innermost.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    // Pops the locals declared inside the loop body without forgetting them, the code
+    // after the jump is still compiled in their scope.
+    fn discard_loop_locals(&mut self, loop_depth: usize) {
+        let captured: Vec<bool> = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth > loop_depth))
+            .map(|local| local.is_captured)
+            .collect();
+        for is_captured in captured {
+            if is_captured {
+                self.emit_op(OpCode::OpCloseUpvalue);
+            } else {
+                self.emit_op(OpCode::OpPop);
+            }
+        }
+    }
+
+    fn break_statement(&mut self) {
+        let Some(loop_depth) = self
+            .state()
+            .loops
+            .last()
+            .map(|innermost| innermost.scope_depth)
+        else {
+            self.error("E0108", "Can't use 'break' outside of a loop.");
+            return;
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+
+        self.discard_loop_locals(loop_depth);
+        let jump = self.emit_jump(OpCode::OpJump);
+        if let Some(innermost) = self.state_mut().loops.last_mut() {
+            innermost.break_jumps.push(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        let Some((loop_start, loop_depth)) = self
+            .state()
+            .loops
+            .last()
+            .map(|innermost| (innermost.start, innermost.scope_depth))
+        else {
+            self.error("E0108", "Can't use 'continue' outside of a loop.");
+            return;
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+
+        self.discard_loop_locals(loop_depth);
+        self.emit_loop(loop_start);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_op(OpCode::OpPrint);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_op(OpCode::OpPop);
+    }
+
+    // Parsing functions, one per entry of the rules table
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::OpCall.into(), arg_count);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == PARAMETERS_MAX {
+                    self.error("E0103", "Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.previous_lexeme());
+        let token = self.previous_position();
+
+        if can_assign && self.match_(TokenType::Equal) {
+            self.expression();
+            self.emit_with_operand_at(OpCode::OpSetProperty, name, token);
+        } else if self.match_(TokenType::LeftParen) {
+            // Call the method directly instead of creating a bound method first.
+            let arg_count = self.argument_list();
+            self.emit_with_operand_at(OpCode::OpInvoke, name, token);
+            self.emit_byte_at(arg_count, token);
+        } else {
+            self.emit_with_operand(OpCode::OpGetProperty, name);
+        }
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("E0109", "Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("E0109", "Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => {}
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.previous_lexeme());
+        let token = self.previous_position();
+
+        self.named_variable("this", false);
+        if self.match_(TokenType::LeftParen) {
+            // Look the method up and call it without creating a bound method first.
+            let arg_count = self.argument_list();
+            self.named_variable("super", false);
+            self.emit_with_operand_at(OpCode::OpSuperInvoke, name, token);
+            self.emit_byte_at(arg_count, token);
+        } else {
+            self.named_variable("super", false);
+            self.emit_with_operand_at(OpCode::OpGetSuper, name, token);
+        }
+    }
+
+    fn this_(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("E0110", "Can't use 'this' outside of a class.");
+            return;
+        }
+        // `this` is the local in the first slot of a method and can't be assigned to.
+        self.variable(false);
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.previous_lexeme(), can_assign);
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        let token = self.previous_position();
+        let state = self.states.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(state, name) {
+            (OpCode::OpGetLocal, OpCode::OpSetLocal, slot as usize)
+        } else if let Some(upvalue) = self.resolve_upvalue(state, name) {
+            (OpCode::OpGetUpvalue, OpCode::OpSetUpvalue, upvalue as usize)
+        } else {
+            let global = self.identifier_constant(name);
+            (OpCode::OpGetGlobal, OpCode::OpSetGlobal, global)
+        };
+
+        if can_assign && self.match_(TokenType::Equal) {
+            self.expression();
+            self.emit_with_operand_at(set_op, arg, token);
+        } else {
+            self.emit_with_operand_at(get_op, arg, token);
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        match self.previous_lexeme().parse::<f64>() {
+            Ok(value) => self.emit_constant(Value::number(value)),
+            Err(_) => self.error("E0112", "Invalid number literal."),
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        // Strip the surrounding quotes from the lexeme.
+        let lexeme = self.previous_lexeme();
+        let string = self.heap.copy_string(&lexeme[1..lexeme.len() - 1]);
+        self.emit_constant(Value::obj(string));
+    }
+
+    fn and_(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+
+        self.emit_op(OpCode::OpPop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or_(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::OpJump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::OpPop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous().type_ {
+            TokenType::False => self.emit_op(OpCode::OpFalse),
+            TokenType::Nil => self.emit_op(OpCode::OpNil),
+            TokenType::True => self.emit_op(OpCode::OpTrue),
+            _ => unreachable!("literal called with a non literal token"),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator_type = self.previous().type_;
+        let operator = self.previous_position();
+
+        // Compile the operand.
+        self.parse_precedence(Precedence::Unary);
+
+        let op = match operator_type {
+            TokenType::Bang => OpCode::OpNot,
+            TokenType::Minus => OpCode::OpNegate,
+            _ => unreachable!("unary called with a non unary operator"),
+        };
+        self.emit_ops_at(&[op], operator);
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator_type = self.previous().type_;
+        let operator = self.previous_position();
+        let rule = get_rule(operator_type);
+        self.parse_precedence(rule.precedence.next());
+
+        let ops: &[OpCode] = match operator_type {
+            TokenType::BangEqual => &[OpCode::OpEqual, OpCode::OpNot],
+            TokenType::EqualEqual => &[OpCode::OpEqual],
+            TokenType::Greater => &[OpCode::OpGreater],
+            TokenType::GreaterEqual => &[OpCode::OpLess, OpCode::OpNot],
+            TokenType::Less => &[OpCode::OpLess],
+            TokenType::LessEqual => &[OpCode::OpGreater, OpCode::OpNot],
+            TokenType::Plus => &[OpCode::OpAdd],
+            TokenType::Minus => &[OpCode::OpSubtract],
+            TokenType::Star => &[OpCode::OpMultiply],
+            TokenType::Slash => &[OpCode::OpDevide],
+            _ => unreachable!("binary called with a non binary operator"),
+        };
+        self.emit_ops_at(ops, operator);
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+        let prefix_rule = match get_rule(self.previous().type_).prefix {
+            Some(rule) => rule,
+            None => {
+                self.error("E0101", "Expect expression.");
+                return;
+            }
+        };
+        // Only a low precedence expression can be the target of an assignment.
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule(self, can_assign);
+
+        while precedence <= get_rule(self.current().type_).precedence {
+            self.advance();
+            if let Some(infix_rule) = get_rule(self.previous().type_).infix {
+                infix_rule(self, can_assign);
+            }
+        }
+
+        if can_assign && self.match_(TokenType::Equal) {
+            self.error("E0102", "Invalid assignment target.");
+        }
+    }
+
+    // Error reporting
+
+    fn error_at_current(&mut self, code: &'static str, message: &str) {
+        let token = self.parser.current;
+        self.error_at(token.as_ref(), code, message);
+    }
+
+    fn error(&mut self, code: &'static str, message: &str) {
+        let token = self.parser.previous;
+        self.error_at(token.as_ref(), code, message);
+    }
+
+    fn error_at(&mut self, token: Option<&Token>, code: &'static str, message: &str) {
+        let diagnostic = Diagnostic::error(code, message);
+        let diagnostic = match token {
+            Some(tok) if tok.type_ == TokenType::Eof => {
+                diagnostic.with_primary(tok.span, "end of input")
             }
+            Some(tok) => diagnostic.with_primary(tok.span, ""),
+            None => diagnostic,
+        };
+        self.report(LoxError::Compile(diagnostic));
+    }
+
+    // Only the first error is reported until the parser gets back in sync.
+    fn report(&mut self, error: LoxError) {
+        if self.parser.panic_mode || self.errors.len() >= self.max_errors.get() {
+            return;
+        }
+        self.parser.panic_mode = true;
+        self.errors.push(error);
+    }
+}
+
+#[cfg(test)]
+mod test_compiler {
+    use std::num::NonZeroUsize;
+
+    use crate::common::{OpCode, Value};
+    use crate::compiler::Compiler;
+    use crate::diagnostics::Diagnostic;
+    use crate::errors::LoxError;
+    use crate::memory::Heap;
+    use crate::scanner::Span;
 
-            self.error_at_current(msg)
-        }
-    }
-
-    fn error_at_current(&self, message: String) {
-        todo!()
-    }
-}
-
-// impl Compiler {
-//     pub fn new(source: &str) -> Self {
-//         Self {
-//             previous: None,
-//             current: None,
-//
-//             scanner: Scanner::new(source),
-//             tokens: vec![],
-//
-//             had_error: false,
-//             panic_mode: false,
-//         }
-//     }
-//
-//     pub fn compile(&mut self) -> bool {
-//         self.advance();
-//         self.expression();
-//         self.consume(TokenType::Eof, &"fuck this shit");
-//         return !self.had_error;
-//     }
-//
-//     pub fn advance(&mut self) -> {
-//         // ToDo: This should retrieve next token from scanner
-//         loop {
-//             self.previous = self.current.take();
-//             self.current = self.scanner.scan_token();
-//
-//             if self.current.is_some() {
-//                 break;
-//             }
-//         }
-//         Ok(())
-//     }
-//
-//     fn scan_token(&mut self) -> Result<Option<Token>, CompilerError> {
-//         match self.scanner.scan_token() {
-//             Ok(tok) => Ok(Some(tok)),
-//             Err(err) => Err(CompilerError::from_scanner_error(err)),
-//         }
-//     }
-//
-//     fn handle_tokenizer_error(&mut self, err: ScannerError) {
-//         if self.panic_mode {
-//             return;
-//         }
-//         self.panic_mode = true;
-//         eprintln!("[line {:}] Error: {:}", err.line, err.message);
-//         self.had_error = true;
-//     }
-//
-//     pub fn consume(&mut self, tok_type: TokenType, message: &str) -> Result<(), CompilerError> {
-//         let should_advance = match &self.current {
-//             Some(tok) => tok.type_ == tok_type,
-//             None => false,
-//         };
-//
-//         if should_advance {
-//             self.advance();
-//             return;
-//         }
-//
-//         self.handle_parser_error(message)
-//     }
-//
-//     pub fn expression(&mut self) {
-//         // ToDo: evaluates expression
-//         todo!()
-//     }
-//
-//     pub fn handle_parser_error(&mut self, message: &str) {
-//         if self.panic_mode {
-//             return;
-//         }
-//         self.panic_mode = true;
-//
-//         let part = match &self.current {
-//             Some(tok) if tok.type_ == TokenType::Eof => " at end".to_string(),
-//             Some(tok) => format!("at line {:}", tok.line),
-//             None => "previous error at scanner".to_string(),
-//         };
-//
-//         self.had_error = true;
-//     }
-// }
-//
-// mod test_compiler {
-//     use crate::compiler::Compiler;
-//
-//     #[test]
-//     fn test_compiler() {
-//         assert!(true);
-//     }
-// }
+    // Compiles the source and returns the bytecode of the top level script.
+    fn compile_code(source: &str) -> Option<Vec<u8>> {
+        let mut heap = Heap::default();
+        Compiler::new(source, &mut heap, &|_| {})
+            .compile()
+            .ok()
+            .map(|function| function.as_function().chunk.code.clone())
+    }
+
+    #[test]
+    fn test_compile_arithmetic() {
+        let code = compile_code("1 + 2 * 3;").unwrap();
+        assert_eq!(
+            code,
+            vec![
+                OpCode::OpConstant as u8,
+                0,
+                OpCode::OpConstant as u8,
+                1,
+                OpCode::OpConstant as u8,
+                2,
+                OpCode::OpMultiply as u8,
+                OpCode::OpAdd as u8,
+                OpCode::OpPop as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_grouping_and_negate() {
+        let code = compile_code("-(1 - 2) / 3;").unwrap();
+        assert_eq!(
+            code,
+            vec![
+                OpCode::OpConstant as u8,
+                0,
+                OpCode::OpConstant as u8,
+                1,
+                OpCode::OpSubtract as u8,
+                OpCode::OpNegate as u8,
+                OpCode::OpConstant as u8,
+                2,
+                OpCode::OpDevide as u8,
+                OpCode::OpPop as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_comparison() {
+        let code = compile_code("!(1 <= 2) == (3 != 4);").unwrap();
+        assert_eq!(
+            code,
+            vec![
+                OpCode::OpConstant as u8,
+                0,
+                OpCode::OpConstant as u8,
+                1,
+                OpCode::OpGreater as u8,
+                OpCode::OpNot as u8,
+                OpCode::OpNot as u8,
+                OpCode::OpConstant as u8,
+                2,
+                OpCode::OpConstant as u8,
+                3,
+                OpCode::OpEqual as u8,
+                OpCode::OpNot as u8,
+                OpCode::OpEqual as u8,
+                OpCode::OpPop as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_literals() {
+        let code = compile_code("!nil == (true != false);").unwrap();
+        assert_eq!(
+            code,
+            vec![
+                OpCode::OpNil as u8,
+                OpCode::OpNot as u8,
+                OpCode::OpTrue as u8,
+                OpCode::OpFalse as u8,
+                OpCode::OpEqual as u8,
+                OpCode::OpNot as u8,
+                OpCode::OpEqual as u8,
+                OpCode::OpPop as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_string() {
+        let mut heap = Heap::default();
+        let function = Compiler::new("\"lox\" + \"\";", &mut heap, &|_| {})
+            .compile()
+            .unwrap();
+        let chunk = &function.as_function().chunk;
+        assert_eq!(chunk.get_constant(0).to_string(), "lox");
+        assert_eq!(chunk.get_constant(1).to_string(), "");
+        assert_eq!(*chunk.get_constant(0), Value::obj(heap.copy_string("lox")));
+    }
+
+    #[test]
+    fn test_compile_globals() {
+        let code = compile_code("var a = 1; a = a; print a;").unwrap();
+        assert_eq!(
+            code,
+            vec![
+                OpCode::OpConstant as u8,
+                1,
+                OpCode::OpDefineGlobal as u8,
+                0,
+                OpCode::OpGetGlobal as u8,
+                0,
+                OpCode::OpSetGlobal as u8,
+                0,
+                OpCode::OpPop as u8,
+                OpCode::OpGetGlobal as u8,
+                0,
+                OpCode::OpPrint as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_locals() {
+        assert!(compile_code("{ var a = 1; { var a = a; } a = 2; }").is_none());
+
+        let code = compile_code("{ var a = 1; { var b = a; b = 2; } }").unwrap();
+        assert_eq!(
+            code,
+            vec![
+                OpCode::OpConstant as u8,
+                0,
+                OpCode::OpGetLocal as u8,
+                1,
+                OpCode::OpConstant as u8,
+                1,
+                OpCode::OpSetLocal as u8,
+                2,
+                OpCode::OpPop as u8,
+                OpCode::OpPop as u8,
+                OpCode::OpPop as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_local_errors() {
+        assert!(compile_code("{ var a = 1; var a = 2; }").is_none());
+        assert!(compile_code("{ var a = 1; { var a = 2; } }").is_some());
+        assert!(compile_code("{ var a = 1;").is_none());
+    }
+
+    #[test]
+    fn test_compile_if_else() {
+        let code = compile_code("if (true) print 1; else print 2;").unwrap();
+        assert_eq!(
+            code,
+            vec![
+                OpCode::OpTrue as u8,
+                OpCode::OpJumpIfFalse as u8,
+                0,
+                7,
+                OpCode::OpPop as u8,
+                OpCode::OpConstant as u8,
+                0,
+                OpCode::OpPrint as u8,
+                OpCode::OpJump as u8,
+                0,
+                4,
+                OpCode::OpPop as u8,
+                OpCode::OpConstant as u8,
+                1,
+                OpCode::OpPrint as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_while() {
+        let code = compile_code("while (false) print 1;").unwrap();
+        assert_eq!(
+            code,
+            vec![
+                OpCode::OpFalse as u8,
+                OpCode::OpJumpIfFalse as u8,
+                0,
+                7,
+                OpCode::OpPop as u8,
+                OpCode::OpConstant as u8,
+                0,
+                OpCode::OpPrint as u8,
+                OpCode::OpLoop as u8,
+                0,
+                11,
+                OpCode::OpPop as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_break_continue_errors() {
+        assert!(compile_code("break;").is_none());
+        assert!(compile_code("{ continue; }").is_none());
+        assert!(compile_code("while (true) break").is_none());
+        assert!(compile_code("for (;;) { if (true) break; continue; }").is_some());
+    }
+
+    #[test]
+    fn test_compile_function() {
+        let mut heap = Heap::default();
+        let script = Compiler::new(
+            "fun add(a, b) { return a + b; } add(1, 2);",
+            &mut heap,
+            &|_| {},
+        )
+        .compile()
+        .unwrap();
+        let Some(add) = script.as_function().chunk.get_constant(1).as_obj() else {
+            panic!("expected the function constant");
+        };
+        assert_eq!(add.to_string(), "<fn add>");
+        assert_eq!(add.as_function().arity, 2);
+        assert_eq!(
+            add.as_function().chunk.code,
+            vec![
+                OpCode::OpGetLocal as u8,
+                1,
+                OpCode::OpGetLocal as u8,
+                2,
+                OpCode::OpAdd as u8,
+                OpCode::OpReturn as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+        assert_eq!(script.to_string(), "<script>");
+    }
+
+    #[test]
+    fn test_compile_closure() {
+        let code = compile_code("{ var a = 1; fun f() { return a; } }").unwrap();
+        assert_eq!(
+            code,
+            vec![
+                OpCode::OpConstant as u8,
+                0,
+                OpCode::OpClosure as u8,
+                1,
+                1,
+                1,
+                OpCode::OpPop as u8,
+                OpCode::OpCloseUpvalue as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_class_errors() {
+        assert!(compile_code("print this;").is_none());
+        assert!(compile_code("fun f() { return this; }").is_none());
+        assert!(compile_code("class A { init() { return 1; } }").is_none());
+        assert!(compile_code("class A { init() { return; } m() { return this; } }").is_some());
+        assert!(compile_code("class A { m() { fun f() { return this; } } }").is_some());
+        assert!(compile_code("var a; a.b.c = 1; a.m(1, 2);").is_some());
+        assert!(compile_code("var a; a.b + 1 = 2;").is_none());
+    }
+
+    #[test]
+    fn test_compile_superclass_errors() {
+        assert!(compile_code("class A < A {}").is_none());
+        assert!(compile_code("print super.m;").is_none());
+        assert!(compile_code("class A { m() { super.m(); } }").is_none());
+        assert!(compile_code("class A {} class B < A { m() { super.m; } }").is_some());
+        assert!(compile_code("{ class A {} class B < A { m() { super.m(); } } }").is_some());
+        assert!(compile_code("class A {} class B < A { m() { super; } }").is_none());
+    }
+
+    #[test]
+    fn test_compile_constant_long() {
+        let source: String = (0..257).map(|i| format!("{};", i)).collect();
+        let code = compile_code(&source).unwrap();
+        assert_eq!(
+            code[code.len() - 7..],
+            [
+                OpCode::OpConstantLong as u8,
+                0,
+                1,
+                0,
+                OpCode::OpPop as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_wide_operands() {
+        let source: String = (0..300).map(|i| format!("{};", i)).collect();
+        let code = compile_code(&(source + "print a;")).unwrap();
+        assert_eq!(
+            code[code.len() - 8..],
+            [
+                OpCode::OpWide as u8,
+                OpCode::OpGetGlobal as u8,
+                0,
+                1,
+                44,
+                OpCode::OpPrint as u8,
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_error() {
+        assert!(compile_code("1 +").is_none());
+        assert!(compile_code("(1;").is_none());
+        assert!(compile_code("1").is_none());
+        assert!(compile_code("1 + 2 = 3;").is_none());
+    }
+
+    #[test]
+    fn test_compile_diagnostics() {
+        let mut heap = Heap::default();
+        let source = "{ var a = 1;\n  var a = 2; }";
+        let Err(errors) = Compiler::new(source, &mut heap, &|_| {}).compile() else {
+            panic!("a is declared twice");
+        };
+        let diagnostics: Vec<Diagnostic> = errors.iter().map(LoxError::diagnostic).collect();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, "E0104");
+        assert_eq!(diagnostics[0].position(source), Some((2, 7)));
+        assert_eq!(diagnostics[0].labels[1].span, Span { start: 6, end: 7 });
+
+        let Err(errors) = Compiler::new("print @;", &mut heap, &|_| {}).compile() else {
+            panic!("@ is not a token");
+        };
+        assert!(matches!(&errors[0], LoxError::Scan(diagnostic) if diagnostic.code == "E0001"));
+    }
+
+    #[test]
+    fn test_compile_synchronize() {
+        let mut heap = Heap::default();
+        let source = "print 1 +;\nvar = 3;\nprint 2;\nfun f( { }\nclass { }";
+        let Err(errors) = Compiler::new(source, &mut heap, &|_| {}).compile() else {
+            panic!("the source has errors");
+        };
+        let diagnostics: Vec<Diagnostic> = errors.iter().map(LoxError::diagnostic).collect();
+        let lines: Vec<usize> = diagnostics
+            .iter()
+            .filter_map(|diagnostic| diagnostic.position(source))
+            .map(|(line, _)| line)
+            .collect();
+        assert_eq!(lines, [1, 2, 4, 5]);
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.notes.is_empty()));
+
+        let Err(errors) = Compiler::new(source, &mut heap, &|_| {})
+            .max_errors(NonZeroUsize::new(2).unwrap())
+            .compile()
+        else {
+            panic!("the source has errors");
+        };
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1].diagnostic().notes, ["stopped after 2 errors"]);
+
+        let Err(errors) = Compiler::new(source, &mut heap, &|_| {})
+            .max_errors(NonZeroUsize::MIN)
+            .compile()
+        else {
+            panic!("the source has errors");
+        };
+        assert_eq!(errors[0].diagnostic().notes, ["stopped after 1 error"]);
+
+        // Reaching the cap on the last error skips nothing.
+        for (source, max_errors) in [(source, 4), ("print 1 +;", 1)] {
+            let Err(errors) = Compiler::new(source, &mut heap, &|_| {})
+                .max_errors(NonZeroUsize::new(max_errors).unwrap())
+                .compile()
+            else {
+                panic!("the source has errors");
+            };
+            assert_eq!(errors.len(), max_errors);
+            assert!(errors
+                .iter()
+                .all(|error| error.diagnostic().notes.is_empty()));
+        }
+
+        // The cap never stops a program without errors from compiling.
+        assert!(Compiler::new("print 1;", &mut heap, &|_| {})
+            .max_errors(NonZeroUsize::MIN)
+            .compile()
+            .is_ok());
+    }
+}
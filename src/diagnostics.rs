@@ -0,0 +1,337 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::scanner::Span;
+
+// How diagnostics are printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    // Source snippets with underlines, meant for people.
+    #[default]
+    Human,
+    // One JSON object per line, meant for tools.
+    Json,
+}
+
+// Points at a piece of the source, the primary label marks the error itself and
+// secondary labels mark related code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+// An error report in the style of rustc. Spans are only meaningful together with the
+// source they were taken from, which is passed in when rendering.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    // Identifies the kind of error, E00xx for the scanner, E01xx for the compiler and
+    // E02xx for the runtime.
+    pub code: &'static str,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+            help: Vec::new(),
+        }
+    }
+
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: true,
+        });
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help.push(help.into());
+        self
+    }
+
+    fn primary(&self) -> Option<&Label> {
+        self.labels.iter().find(|label| label.primary)
+    }
+
+    // Line and column of the primary label.
+    pub fn position(&self, source: &str) -> Option<(usize, usize)> {
+        Some(line_and_column(source, self.primary()?.span.start))
+    }
+
+    // `file` names the source in the report, diagnostics do not know where their source
+    // came from.
+    pub fn format(&self, format: ErrorFormat, file: &str, source: &str) -> String {
+        match format {
+            ErrorFormat::Human => self.render(file, source),
+            ErrorFormat::Json => self.to_json(file, source),
+        }
+    }
+
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let mut lines = vec![self.to_string()];
+
+        let mut labels: Vec<(Location, &Label)> = self
+            .labels
+            .iter()
+            .map(|label| (locate(source, label.span.start), label))
+            .collect();
+        labels.sort_by_key(|(location, _)| (location.line, location.column));
+        let width = labels
+            .iter()
+            .map(|(location, _)| location.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = format!("{:width$} |", "");
+
+        if let Some((line, column)) = self.position(source) {
+            lines.push(format!("{:width$}--> {}:{}:{}", "", file, line, column));
+        }
+        if !labels.is_empty() {
+            lines.push(gutter.clone());
+        }
+        let mut previous_line = None;
+        for (location, label) in &labels {
+            if previous_line != Some(location.line) {
+                lines.push(format!("{:>width$} | {}", location.line, location.text));
+                previous_line = Some(location.line);
+            }
+            // Tabs are kept so the underline lines up with the source above it.
+            let indent: String = location.text[..location.offset]
+                .chars()
+                .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                .collect();
+            let end = label
+                .span
+                .end
+                .min(location.line_start + location.text.len());
+            let length = source
+                .get(label.span.start..end)
+                .map_or(0, |text| text.chars().count())
+                .max(1);
+            let marker = if label.primary { "^" } else { "-" };
+            let underline = format!("{} {}{}", gutter, indent, marker.repeat(length));
+            if label.message.is_empty() {
+                lines.push(underline);
+            } else {
+                lines.push(format!("{} {}", underline, label.message));
+            }
+        }
+
+        let has_notes = !self.notes.is_empty() || !self.help.is_empty();
+        if !labels.is_empty() && has_notes {
+            lines.push(gutter);
+        }
+        for note in &self.notes {
+            lines.push(format!("{:width$} = note: {}", "", note));
+        }
+        for help in &self.help {
+            lines.push(format!("{:width$} = help: {}", "", help));
+        }
+        lines.join("\n")
+    }
+
+    // A single line of JSON, the span of the primary label is repeated at the top level so
+    // simple consumers can ignore the labels.
+    pub fn to_json(&self, file: &str, source: &str) -> String {
+        let span = self
+            .primary()
+            .map_or("null".to_string(), |label| span_json(source, label.span));
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|label| {
+                format!(
+                    "{{\"span\":{},\"message\":{},\"primary\":{}}}",
+                    span_json(source, label.span),
+                    json_string(&label.message),
+                    label.primary
+                )
+            })
+            .collect();
+        let strings = |texts: &[String]| -> String {
+            let texts: Vec<String> = texts.iter().map(|text| json_string(text)).collect();
+            format!("[{}]", texts.join(","))
+        };
+        format!(
+            "{{\"file\":{},\"severity\":\"error\",\"code\":{},\"message\":{},\"span\":{},\
+             \"labels\":[{}],\"notes\":{},\"help\":{}}}",
+            json_string(file),
+            json_string(self.code),
+            json_string(&self.message),
+            span,
+            labels.join(","),
+            strings(&self.notes),
+            strings(&self.help)
+        )
+    }
+}
+
+// The headline of the report, rendering needs the source.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error[{}]: {}", self.code, self.message)
+    }
+}
+
+impl Error for Diagnostic {}
+
+fn span_json(source: &str, span: Span) -> String {
+    let location = locate(source, span.start);
+    format!(
+        "{{\"start\":{},\"end\":{},\"line\":{},\"column\":{}}}",
+        span.start, span.end, location.line, location.column
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            ch if ch.is_control() => quoted.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// The line a byte offset falls on.
+struct Location<'src> {
+    line: usize,
+    // Counted in characters from 1.
+    column: usize,
+    text: &'src str,
+    // Byte offsets of the line in the source and of the position in the line.
+    line_start: usize,
+    offset: usize,
+}
+
+fn locate(source: &str, offset: usize) -> Location<'_> {
+    let offset = offset.min(source.len());
+    let line_start = source[..offset]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let line_end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |newline| offset + newline);
+    let text = &source[line_start..line_end];
+    Location {
+        line: source[..line_start].matches('\n').count() + 1,
+        column: source[line_start..offset].chars().count() + 1,
+        text,
+        line_start,
+        offset: offset - line_start,
+    }
+}
+
+// Line and column of a byte offset, both counted from 1.
+pub fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let location = locate(source, offset);
+    (location.line, location.column)
+}
+
+#[cfg(test)]
+mod test_diagnostics {
+    use crate::diagnostics::{line_and_column, Diagnostic, ErrorFormat};
+    use crate::scanner::Span;
+
+    #[test]
+    fn test_render() {
+        let source = "var a = 1;\n{\n  var b = 2;\n  var b = 3;\n}";
+        let diagnostic = Diagnostic::error("E0104", "Already a variable with this name.")
+            .with_primary(Span { start: 32, end: 33 }, "redeclared here")
+            .with_label(Span { start: 19, end: 20 }, "first declared here")
+            .with_help("use a different name");
+        assert_eq!(
+            diagnostic.render("test.lox", source),
+            "error[E0104]: Already a variable with this name.\n \
+             --> test.lox:4:7\n  \
+             |\n\
+             3 |   var b = 2;\n  \
+             |       - first declared here\n\
+             4 |   var b = 3;\n  \
+             |       ^ redeclared here\n  \
+             |\n  \
+             = help: use a different name"
+        );
+    }
+
+    #[test]
+    fn test_render_multibyte_and_end() {
+        let source = "print \"é\" +";
+        let diagnostic = Diagnostic::error("E0101", "Expect expression.")
+            .with_primary(Span { start: 6, end: 10 }, "")
+            .with_label(Span { start: 12, end: 12 }, "end of input");
+        assert_eq!(
+            diagnostic.render("test.lox", source),
+            "error[E0101]: Expect expression.\n \
+             --> test.lox:1:7\n  \
+             |\n\
+             1 | print \"é\" +\n  \
+             |       ^^^\n  \
+             |            - end of input"
+        );
+        assert_eq!(diagnostic.position(source), Some((1, 7)));
+    }
+
+    #[test]
+    fn test_line_and_column() {
+        let source = "a\nbé c\n";
+        assert_eq!(line_and_column(source, 3), (2, 2));
+        assert_eq!(line_and_column(source, 6), (2, 4));
+        assert_eq!(line_and_column(source, 0), (1, 1));
+    }
+
+    #[test]
+    fn test_json() {
+        let source = "print \"a\\tb\" +";
+        let diagnostic = Diagnostic::error("E0101", "Expect expression.")
+            .with_primary(Span { start: 14, end: 14 }, "end of input")
+            .with_help("say \"what\"");
+        assert_eq!(
+            diagnostic.format(ErrorFormat::Json, "dir\\a.lox", source),
+            "{\"file\":\"dir\\\\a.lox\",\"severity\":\"error\",\"code\":\"E0101\",\
+             \"message\":\"Expect expression.\",\
+             \"span\":{\"start\":14,\"end\":14,\"line\":1,\"column\":15},\
+             \"labels\":[{\"span\":{\"start\":14,\"end\":14,\"line\":1,\"column\":15},\
+             \"message\":\"end of input\",\"primary\":true}],\
+             \"notes\":[],\"help\":[\"say \\\"what\\\"\"]}"
+        );
+        assert_eq!(
+            Diagnostic::error("E0206", "Could not write.").to_json("<repl>", ""),
+            "{\"file\":\"<repl>\",\"severity\":\"error\",\"code\":\"E0206\",\
+             \"message\":\"Could not write.\",\"span\":null,\"labels\":[],\"notes\":[],\"help\":[]}"
+        );
+    }
+}
@@ -1,3 +1,96 @@
-pub enum Error {
-    UnknownOpCode,
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::diagnostics::Diagnostic;
+
+// Everything that can go wrong while running Lox code. Scan, compile and runtime errors
+// carry the diagnostic that was reported for them, which is also their source.
+#[derive(Debug)]
+pub enum LoxError {
+    Io(io::Error),
+    Scan(Diagnostic),
+    Compile(Diagnostic),
+    Runtime(Diagnostic),
+    // A byte that does not decode to an opcode where one was expected.
+    InvalidBytecode(u8),
+}
+
+impl LoxError {
+    // The report shown for the error, errors without one get a diagnostic with no
+    // position.
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            LoxError::Io(err) => Diagnostic::error("E0206", format!("I/O error: {}.", err)),
+            LoxError::Scan(diagnostic)
+            | LoxError::Compile(diagnostic)
+            | LoxError::Runtime(diagnostic) => diagnostic.clone(),
+            LoxError::InvalidBytecode(byte) => {
+                Diagnostic::error("E0207", format!("Invalid opcode {}.", byte))
+            }
+        }
+    }
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxError::Io(_) => write!(f, "I/O error"),
+            LoxError::Scan(_) => write!(f, "scan error"),
+            LoxError::Compile(_) => write!(f, "compile error"),
+            LoxError::Runtime(_) => write!(f, "runtime error"),
+            LoxError::InvalidBytecode(byte) => write!(f, "invalid opcode {}", byte),
+        }
+    }
+}
+
+impl Error for LoxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoxError::Io(err) => Some(err),
+            LoxError::Scan(diagnostic)
+            | LoxError::Compile(diagnostic)
+            | LoxError::Runtime(diagnostic) => Some(diagnostic),
+            LoxError::InvalidBytecode(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoxError {
+    fn from(err: io::Error) -> Self {
+        LoxError::Io(err)
+    }
+}
+
+// Diagnostics raised while running code, so `?` works in the vm.
+impl From<Diagnostic> for LoxError {
+    fn from(diagnostic: Diagnostic) -> Self {
+        LoxError::Runtime(diagnostic)
+    }
+}
+
+#[cfg(test)]
+mod test_errors {
+    use std::error::Error;
+    use std::io;
+
+    use crate::diagnostics::Diagnostic;
+    use crate::errors::LoxError;
+
+    #[test]
+    fn test_source_chain() {
+        let error = LoxError::Compile(Diagnostic::error("E0101", "Expect expression."));
+        assert_eq!(error.to_string(), "compile error");
+        let source = error.source().unwrap();
+        assert_eq!(source.to_string(), "error[E0101]: Expect expression.");
+        assert!(source.source().is_none());
+
+        let error = LoxError::from(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert_eq!(error.source().unwrap().to_string(), "no such file");
+        assert_eq!(error.diagnostic().message, "I/O error: no such file.");
+
+        let error = LoxError::InvalidBytecode(255);
+        assert_eq!(error.to_string(), "invalid opcode 255");
+        assert!(error.source().is_none());
+    }
 }
@@ -1,83 +1,151 @@
-use std::{io, io::prelude::*};
+use std::io::prelude::*;
 
 use std::process::exit;
 
+use errors::LoxError;
+
 mod common;
 mod compiler;
+mod diagnostics;
 mod errors;
+mod memory;
 mod scanner;
 mod vm;
 
-#[derive(Debug)]
-struct IOError;
-
-fn prompt(name: &str) -> String {
+// Returns None once stdin reaches end of file.
+fn prompt(name: &str) -> Option<String> {
     let mut line = String::new();
     print!("{}", name);
     std::io::stdout().flush().unwrap();
-    std::io::stdin()
+    let read = std::io::stdin()
         .read_line(&mut line)
         .expect("Error: Could not read a line");
-    return line.trim().to_string();
+    if read == 0 {
+        return None;
+    }
+    Some(line.trim().to_string())
 }
 
-fn run_script(instance: &vm::VM, script_path: &String) -> Result<(), IOError> {
-    let file = match std::fs::File::open(script_path) {
-        Ok(k) => k,
-        Err(e) => {
-            return Err(IOError);
+// Errors in the script are reported by the vm, only reading it is reported here.
+fn run_script(instance: &mut vm::VM, script_path: &str) -> Result<(), LoxError> {
+    let contents = match std::fs::read_to_string(script_path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            let error = LoxError::from(err);
+            let diagnostic = error
+                .diagnostic()
+                .with_note(format!("could not read file \"{}\"", script_path));
+            instance.report(&diagnostic, script_path, "");
+            return Err(error);
         }
     };
-    let mut buf_reader = io::BufReader::new(file);
-    let mut contents = String::new();
-    match buf_reader.read_to_string(&mut contents) {
-        Ok(_) => {}
-        Err(e) => {
-            return Err(IOError);
-        }
+    instance.interpret(script_path, &contents)
+}
+
+fn run_repl(instance: &mut vm::VM) {
+    while let Some(line) = prompt("> ") {
+        // The vm already reported the error, the session goes on.
+        let _ = instance.interpret("<repl>", &line);
     }
-    // self.execute_code(&mut contents)?;
-    Ok(())
+    println!();
 }
 
-fn run_repl() {
-    todo!()
+// Exit codes from sysexits.h.
+fn exit_code(error: &LoxError) -> i32 {
+    match error {
+        LoxError::Io(_) => 74,
+        LoxError::Scan(_) | LoxError::Compile(_) => 65,
+        LoxError::Runtime(_) | LoxError::InvalidBytecode(_) => 70,
+    }
+}
+
+fn usage(program_name: &str) -> ! {
+    // Tracing flags only exist in builds with the `trace` feature.
+    let trace_flags = if cfg!(feature = "trace") {
+        "[--trace-execution] [--print-code] "
+    } else {
+        ""
+    };
+    eprintln!(
+        "Usage: {} [--incremental-gc] [--stress-gc] [--log-gc] [--gc-stats] \
+         {}[--error-format=human|json] [--max-errors=N] [path]",
+        program_name, trace_flags
+    );
+    exit(64);
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let instance = vm::VM::new();
+    let mut args: Vec<String> = std::env::args().collect();
+    let mut config = vm::Config::default();
+    let mut unknown_flag = false;
+    let mut gc_stats = false;
+    args.retain(|arg| match arg.as_str() {
+        "--incremental-gc" => {
+            config.gc.mode = memory::GcMode::Incremental;
+            false
+        }
+        "--stress-gc" => {
+            config.gc.stress_gc = true;
+            false
+        }
+        "--log-gc" => {
+            config.gc.log_gc = true;
+            false
+        }
+        "--error-format=human" => {
+            config.error_format = diagnostics::ErrorFormat::Human;
+            false
+        }
+        "--error-format=json" => {
+            config.error_format = diagnostics::ErrorFormat::Json;
+            false
+        }
+        "--gc-stats" => {
+            gc_stats = true;
+            false
+        }
+        #[cfg(feature = "trace")]
+        "--trace-execution" => {
+            config.trace_execution = true;
+            false
+        }
+        #[cfg(feature = "trace")]
+        "--print-code" => {
+            config.print_code = true;
+            false
+        }
+        flag if flag.starts_with("--max-errors=") => {
+            match flag["--max-errors=".len()..].parse() {
+                Ok(max_errors) => config.max_errors = max_errors,
+                _ => unknown_flag = true,
+            }
+            false
+        }
+        _ => {
+            unknown_flag |= arg.starts_with("--");
+            true
+        }
+    });
+    let mut instance = vm::VM::new(config);
 
     match &args[..] {
+        [program_name, ..] if unknown_flag => usage(program_name),
         [_, script_path] => {
-            match run_script(&instance, script_path) {
-                Ok(_) => {}
-                Err(err) => print!("Error: {:?}", err),
-            };
+            let result = run_script(&mut instance, script_path);
+            if gc_stats {
+                eprintln!("{}", instance.gc_stats());
+            }
+            if let Err(error) = result {
+                exit(exit_code(&error));
+            }
         }
         [_] => {
-            todo!()
-        }
-        [program_name, ..] => {
-            exit(64);
+            run_repl(&mut instance);
+            if gc_stats {
+                eprintln!("{}", instance.gc_stats());
+            }
         }
+        [program_name, ..] => usage(program_name),
         _ => unreachable!("Unreacheable"),
     }
-
-    let mut chunk = common::Chunk::new();
-    let a = chunk.add_constants(1.2);
-    chunk.write(common::OpCode::OpConstant as u8, 1);
-    chunk.write(a as u8, 1);
-    let b = chunk.add_constants(3.4);
-    chunk.write(common::OpCode::OpConstant as u8, 1);
-    chunk.write(b as u8, 1);
-    chunk.write(common::OpCode::OpAdd as u8, 1);
-    let c = chunk.add_constants(5.6);
-    chunk.write(common::OpCode::OpConstant as u8, 1);
-    chunk.write(c as u8, 1);
-    chunk.write(common::OpCode::OpDevide as u8, 1);
-    chunk.write(common::OpCode::OpReturn as u8, 1);
-    // chunk.disassemble_chunk("test");
-    let mut vm_instance = vm::VM::new();
-    vm_instance.interpret(&chunk);
 }
@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::common::{
+    Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjRef,
+    ObjString, ObjUpvalue, Value,
+};
+
+// Collections start once this many bytes are allocated.
+const FIRST_GC: usize = 1024 * 1024;
+// The next collection starts once the live heap grew by this factor.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+// How many objects an incremental step traces or sweeps.
+const GC_STEP_WORK: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GcMode {
+    // Every collection marks and sweeps the whole heap in one pause.
+    #[default]
+    StopTheWorld,
+    // Collections are split into small steps run between instructions, keeping pauses
+    // short at the cost of write barriers.
+    Incremental,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GcConfig {
+    pub mode: GcMode,
+    // Collect at every safe point instead of waiting for the threshold, used to shake out
+    // missing roots.
+    pub stress_gc: bool,
+    // Trace allocations and collections on stderr.
+    pub log_gc: bool,
+}
+
+// Where an incremental collection is at, stop-the-world collections go from idle back
+// to idle in one call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Mark,
+    Sweep,
+}
+
+// How long the program was paused by the collector.
+#[derive(Debug, Default)]
+pub struct GcStats {
+    // Completed collections.
+    pub cycles: usize,
+    // Calls into the collector, an incremental collection takes many of them.
+    pub pauses: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+}
+
+impl GcStats {
+    fn record_pause(&mut self, pause: Duration) {
+        self.pauses += 1;
+        self.total_pause += pause;
+        self.max_pause = self.max_pause.max(pause);
+    }
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mean = match self.pauses {
+            0 => Duration::ZERO,
+            pauses => self.total_pause / pauses as u32,
+        };
+        write!(
+            f,
+            "gc: {} cycles, {} pauses, total {:?}, mean {:?}, max {:?}",
+            self.cycles, self.pauses, self.total_pause, mean, self.max_pause
+        )
+    }
+}
+
+// Owns every object allocated by the compiler and the vm. Objects are freed by a
+// mark-sweep collector, the owner of the heap passes a function marking its roots to
+// `collect_garbage`.
+pub struct Heap {
+    config: GcConfig,
+    phase: Phase,
+    objects: Vec<ObjRef>,
+    // Interned strings, every string object is created through this table. The table
+    // does not keep strings alive.
+    strings: HashMap<String, ObjRef>,
+    // Marked objects whose references are not traced yet.
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    // Index of the next object to sweep.
+    sweep_cursor: usize,
+    // Size of the objects that survived the current sweep.
+    live_bytes: usize,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::with_config(GcConfig::default())
+    }
+}
+
+impl Heap {
+    pub fn with_config(config: GcConfig) -> Self {
+        Self {
+            config,
+            objects: Vec::new(),
+            strings: HashMap::new(),
+            gray: Vec::new(),
+            phase: Phase::Idle,
+            bytes_allocated: 0,
+            next_gc: FIRST_GC,
+            sweep_cursor: 0,
+            live_bytes: 0,
+            stats: GcStats::default(),
+        }
+    }
+
+    fn alloc(&mut self, kind: ObjKind) -> ObjRef {
+        // Objects created while an incremental collection runs survive it. They are gray
+        // while marking because they may already reference unmarked objects.
+        let obj = ObjRef::from_box(Box::new(Obj {
+            kind,
+            is_marked: self.phase != Phase::Idle,
+        }));
+        if self.phase == Phase::Mark {
+            self.gray.push(obj);
+        }
+        let size = size_of_obj(&obj);
+        self.bytes_allocated += size;
+        if self.config.log_gc {
+            eprintln!("{:p} allocate {} for {}", &*obj, size, type_name(&obj));
+        }
+        self.objects.push(obj);
+        obj
+    }
+
+    // Returns the interned string, copying `chars` only when it is not interned yet.
+    pub fn copy_string(&mut self, chars: &str) -> ObjRef {
+        if let Some(interned) = self.strings.get(chars) {
+            return *interned;
+        }
+        self.allocate_string(chars.to_string())
+    }
+
+    // Same as `copy_string` but takes ownership of an already built string.
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        if let Some(interned) = self.strings.get(&chars) {
+            return *interned;
+        }
+        self.allocate_string(chars)
+    }
+
+    pub fn alloc_function(&mut self, function: ObjFunction) -> ObjRef {
+        self.alloc(ObjKind::Function(function))
+    }
+
+    pub fn alloc_closure(&mut self, function: ObjRef, upvalues: Vec<ObjRef>) -> ObjRef {
+        self.alloc(ObjKind::Closure(ObjClosure { function, upvalues }))
+    }
+
+    // An upvalue starts open, pointing at the captured stack slot.
+    pub fn alloc_upvalue(&mut self, slot: usize) -> ObjRef {
+        self.alloc(ObjKind::Upvalue(ObjUpvalue::Open(slot)))
+    }
+
+    pub fn alloc_class(&mut self, name: ObjRef) -> ObjRef {
+        self.alloc(ObjKind::Class(ObjClass {
+            name,
+            methods: HashMap::new(),
+        }))
+    }
+
+    pub fn alloc_instance(&mut self, class: ObjRef) -> ObjRef {
+        self.alloc(ObjKind::Instance(ObjInstance {
+            class,
+            fields: HashMap::new(),
+        }))
+    }
+
+    pub fn alloc_bound_method(&mut self, receiver: Value, method: ObjRef) -> ObjRef {
+        self.alloc(ObjKind::BoundMethod(ObjBoundMethod { receiver, method }))
+    }
+
+    fn allocate_string(&mut self, chars: String) -> ObjRef {
+        let key = chars.clone();
+        let string = self.alloc(ObjKind::String(ObjString { chars }));
+        self.strings.insert(key, string);
+        string
+    }
+
+    // Collection only happens at safe points chosen by the owner of the heap, where every
+    // live object is reachable from the roots it marks.
+    pub fn should_collect(&self) -> bool {
+        self.config.stress_gc || self.phase != Phase::Idle || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+
+    // Collects garbage, `mark_roots` marks every object the caller still references. In
+    // incremental mode only a bounded amount of work is done per call and `mark_roots`
+    // is called at the start and at the end of each marking phase.
+    pub fn collect_garbage(&mut self, mark_roots: &dyn Fn(&mut Heap)) {
+        let start = Instant::now();
+        match self.config.mode {
+            GcMode::StopTheWorld => self.collect(mark_roots),
+            GcMode::Incremental => self.step(mark_roots),
+        }
+        self.stats.record_pause(start.elapsed());
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(obj) = value.as_obj() {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, mut obj: ObjRef) {
+        if obj.is_marked {
+            return;
+        }
+        if self.config.log_gc {
+            eprintln!("{:p} mark {:?}", &*obj, obj);
+        }
+        obj.is_marked = true;
+        self.gray.push(obj);
+    }
+
+    pub fn mark_table(&mut self, table: &HashMap<ObjRef, Value>) {
+        for (key, value) in table {
+            self.mark_object(*key);
+            self.mark_value(*value);
+        }
+    }
+
+    // Must be called whenever a value is stored into an object or a global while an
+    // incremental collection is marking, so no traced object ends up pointing to an
+    // object that is not marked.
+    pub fn write_barrier(&mut self, value: Value) {
+        if self.phase == Phase::Mark {
+            self.mark_value(value);
+        }
+    }
+
+    fn collect(&mut self, mark_roots: &dyn Fn(&mut Heap)) {
+        if self.config.log_gc {
+            eprintln!("-- gc begin");
+        }
+        mark_roots(self);
+        self.trace_references(usize::MAX);
+        self.start_sweep();
+        self.sweep(usize::MAX);
+        self.finish_cycle();
+    }
+
+    fn step(&mut self, mark_roots: &dyn Fn(&mut Heap)) {
+        match self.phase {
+            Phase::Idle => {
+                if self.config.log_gc {
+                    eprintln!("-- gc begin");
+                }
+                mark_roots(self);
+                self.phase = Phase::Mark;
+            }
+            Phase::Mark if self.gray.is_empty() => {
+                // Roots are not guarded by write barriers, they are marked once more
+                // before marking ends.
+                mark_roots(self);
+                self.trace_references(usize::MAX);
+                self.start_sweep();
+            }
+            Phase::Mark => self.trace_references(GC_STEP_WORK),
+            Phase::Sweep => {
+                if self.sweep(GC_STEP_WORK) {
+                    self.finish_cycle();
+                }
+            }
+        }
+    }
+
+    // Traces at most `work` gray objects.
+    fn trace_references(&mut self, work: usize) {
+        for _ in 0..work {
+            let Some(obj) = self.gray.pop() else {
+                return;
+            };
+            self.blacken_object(obj);
+        }
+    }
+
+    fn blacken_object(&mut self, obj: ObjRef) {
+        match &obj.kind {
+            ObjKind::String(_) => {}
+            ObjKind::Function(function) => {
+                if let Some(name) = function.name {
+                    self.mark_object(name);
+                }
+                for constant in function.chunk.constants() {
+                    self.mark_value(*constant);
+                }
+            }
+            ObjKind::Closure(closure) => {
+                self.mark_object(closure.function);
+                for upvalue in &closure.upvalues {
+                    self.mark_object(*upvalue);
+                }
+            }
+            ObjKind::Upvalue(ObjUpvalue::Open(_)) => {}
+            ObjKind::Upvalue(ObjUpvalue::Closed(value)) => self.mark_value(*value),
+            ObjKind::Class(class) => {
+                self.mark_object(class.name);
+                self.mark_table(&class.methods);
+            }
+            ObjKind::Instance(instance) => {
+                self.mark_object(instance.class);
+                self.mark_table(&instance.fields);
+            }
+            ObjKind::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+        }
+    }
+
+    fn start_sweep(&mut self) {
+        // Unmarked strings are about to be freed, the table must not hand them out again.
+        self.strings.retain(|_, string| string.is_marked);
+        self.phase = Phase::Sweep;
+        self.sweep_cursor = 0;
+        self.live_bytes = 0;
+    }
+
+    // Frees at most `work` unmarked objects and clears the mark of the others, returns
+    // whether every object was visited.
+    fn sweep(&mut self, work: usize) -> bool {
+        for _ in 0..work {
+            let Some(&obj) = self.objects.get(self.sweep_cursor) else {
+                return true;
+            };
+            let mut obj = obj;
+            if obj.is_marked {
+                obj.is_marked = false;
+                // Objects may have grown since they were allocated, so the live size is
+                // recounted instead of subtracting what gets freed.
+                self.live_bytes += size_of_obj(&obj);
+                self.sweep_cursor += 1;
+                continue;
+            }
+            if self.config.log_gc {
+                eprintln!("{:p} free type {}", &*obj, type_name(&obj));
+            }
+            self.objects.swap_remove(self.sweep_cursor);
+            // Unreachable objects can't be referenced by anything that is still used.
+            unsafe { obj.free() }
+        }
+        self.sweep_cursor == self.objects.len()
+    }
+
+    fn finish_cycle(&mut self) {
+        let before = self.bytes_allocated;
+        self.bytes_allocated = self.live_bytes;
+        self.next_gc =
+            self.bytes_allocated.max(FIRST_GC / GC_HEAP_GROW_FACTOR) * GC_HEAP_GROW_FACTOR;
+        self.phase = Phase::Idle;
+        self.stats.cycles += 1;
+        if self.config.log_gc {
+            eprintln!("-- gc end");
+            eprintln!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                before.saturating_sub(self.bytes_allocated),
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for obj in self.objects.drain(..) {
+            // Nothing can reference the objects once the heap is gone.
+            unsafe { obj.free() }
+        }
+    }
+}
+
+// An estimate of the memory owned by the object, used to pace collections.
+fn size_of_obj(obj: &Obj) -> usize {
+    let entry = mem::size_of::<ObjRef>() + mem::size_of::<Value>();
+    mem::size_of::<Obj>()
+        + match &obj.kind {
+            ObjKind::String(string) => string.chars.capacity(),
+            ObjKind::Function(function) => {
+                function.chunk.code.capacity() + mem::size_of_val(function.chunk.constants())
+            }
+            ObjKind::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<ObjRef>(),
+            ObjKind::Upvalue(_) | ObjKind::BoundMethod(_) => 0,
+            ObjKind::Class(class) => class.methods.capacity() * entry,
+            ObjKind::Instance(instance) => instance.fields.capacity() * entry,
+        }
+}
+
+fn type_name(obj: &Obj) -> &'static str {
+    match obj.kind {
+        ObjKind::String(_) => "string",
+        ObjKind::Function(_) => "function",
+        ObjKind::Closure(_) => "closure",
+        ObjKind::Upvalue(_) => "upvalue",
+        ObjKind::Class(_) => "class",
+        ObjKind::Instance(_) => "instance",
+        ObjKind::BoundMethod(_) => "bound method",
+    }
+}
+
+#[cfg(test)]
+mod test_memory {
+    use super::*;
+
+    #[test]
+    fn test_collect_unreachable() {
+        let mut heap = Heap::default();
+        let kept = heap.copy_string("kept");
+        heap.copy_string("garbage");
+        let class = heap.alloc_class(kept);
+        let instance = heap.alloc_instance(class);
+
+        heap.collect_garbage(&|heap| heap.mark_object(instance));
+
+        assert_eq!(heap.objects.len(), 3);
+        assert!(!heap.strings.contains_key("garbage"));
+        assert_eq!(heap.copy_string("kept"), kept);
+        assert!(!kept.is_marked);
+    }
+
+    #[test]
+    fn test_should_collect() {
+        let mut heap = Heap::default();
+        heap.copy_string("a");
+        assert!(!heap.should_collect());
+        heap.copy_string(&"b".repeat(FIRST_GC));
+        assert!(heap.should_collect());
+        heap.collect_garbage(&|_| {});
+        assert!(!heap.should_collect());
+        assert!(heap.objects.is_empty());
+        assert_eq!(heap.stats().cycles, 1);
+
+        let heap = Heap::with_config(GcConfig {
+            stress_gc: true,
+            ..GcConfig::default()
+        });
+        assert!(heap.should_collect());
+    }
+
+    #[test]
+    fn test_incremental_write_barrier() {
+        let mut heap = Heap::with_config(GcConfig {
+            mode: GcMode::Incremental,
+            ..GcConfig::default()
+        });
+        let stored = heap.copy_string("stored");
+        let name = heap.copy_string("Point");
+        let class = heap.alloc_class(name);
+        let mut instance = heap.alloc_instance(class);
+        let roots = move |heap: &mut Heap| heap.mark_object(instance);
+
+        // Mark the roots, then trace the instance and its class.
+        heap.collect_garbage(&roots);
+        heap.collect_garbage(&roots);
+        assert_eq!(heap.phase, Phase::Mark);
+        assert!(heap.gray.is_empty());
+
+        // The instance is traced already, only the barrier keeps the string alive.
+        instance
+            .as_instance_mut()
+            .fields
+            .insert(name, Value::obj(stored));
+        heap.write_barrier(Value::obj(stored));
+        let garbage = heap.copy_string("garbage");
+        assert!(garbage.is_marked);
+
+        while heap.should_collect() {
+            heap.collect_garbage(&roots);
+        }
+        assert_eq!(heap.stats().cycles, 1);
+        assert!(heap.stats().pauses > 1);
+        assert_eq!(heap.objects.len(), 5);
+        assert_eq!(heap.copy_string("stored"), stored);
+        assert_eq!(heap.copy_string("garbage"), garbage);
+
+        // Objects created during the last cycle are collected by the next one.
+        heap.next_gc = 0;
+        while heap.should_collect() {
+            heap.collect_garbage(&roots);
+        }
+        assert_eq!(heap.objects.len(), 4);
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum TokenType {
     LeftParen,
     RightParen,
@@ -20,7 +20,6 @@ pub enum TokenType {
     Less,
     LessEqual,
     Identifier,
-    Str,
     Number,
     And,
     Class,
@@ -40,52 +39,96 @@ pub enum TokenType {
     While,
     Eof,
     Break,
+    Continue,
     Error,
     String,
 }
 
+use crate::diagnostics::Diagnostic;
+
 const RADIX: u32 = 10;
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct Token {
-    type_: TokenType,
-    lexeme: String, // in the compiler book this is a pointer which works to set the error msg.
-    line: usize,
+// A range of bytes in the source, the end is exclusive.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Token<'src> {
+    pub type_: TokenType,
+    // Borrowed from the source, error tokens point at the offending source too.
+    pub lexeme: &'src str,
+    pub span: Span,
+    // Line and column of the first character, the column is counted in characters from 1.
+    pub line: usize,
+    pub column: usize,
+    // What went wrong for error tokens.
+    pub error: Option<ScanError>,
 }
 
-impl Token {
+impl Token<'_> {
     pub fn is_err(&self) -> bool {
         self.type_ == TokenType::Error
     }
 
-    pub fn message(&self) -> String {
-        self.lexeme.clone()
+    // The report for an error token, pointing at the offending source.
+    pub fn diagnostic(&self) -> Option<Diagnostic> {
+        let error = self.error?;
+        let diagnostic =
+            Diagnostic::error(error.code, error.message).with_primary(self.span, error.label);
+        Some(match error.help {
+            Some(help) => diagnostic.with_help(help),
+            None => diagnostic,
+        })
     }
 }
 
-pub struct Scanner {
-    source: Vec<char>,
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ScanError {
+    pub code: &'static str,
+    pub message: &'static str,
+    pub label: &'static str,
+    pub help: Option<&'static str>,
+}
+
+// Scans the source bytes directly, multi-byte characters are only decoded where they
+// can appear: in strings, identifiers and unexpected characters.
+pub struct Scanner<'src> {
+    source: &'src str,
 
+    // Byte offsets of the start of the current token and of the next character.
     start: usize,
     current: usize,
 
     line: usize,
+    // Column of the next character.
+    current_column: usize,
+    // Line and column the current token starts at.
+    start_line: usize,
+    column: usize,
 }
 
-impl Scanner {
-    pub fn new(source: &str) -> Self {
+impl<'src> Scanner<'src> {
+    pub fn new(source: &'src str) -> Self {
         Self {
-            source: source.chars().collect(),
+            source,
             start: 0,
             current: 0,
             line: 1,
+            current_column: 1,
+            start_line: 1,
+            column: 1,
         }
     }
 
-    pub fn scan_token(&mut self) -> Token {
+    pub fn scan_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
 
         self.start = self.current; // set start of the token
+        self.start_line = self.line;
+        self.column = self.current_column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -139,7 +182,12 @@ impl Scanner {
                     return self.identifier();
                 }
 
-                self.make_error("Unexpected charecter.".to_string())
+                self.make_error(ScanError {
+                    code: "E0001",
+                    message: "Unexpected charecter.",
+                    label: "not valid in Lox",
+                    help: None,
+                })
             }
         }
     }
@@ -152,8 +200,8 @@ impl Scanner {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
                 '/' => {
                     if let Some('/') = self.peek_next() {
@@ -169,61 +217,73 @@ impl Scanner {
         }
     }
 
+    // Called right after consuming a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.current_column = 1;
+    }
+
     pub fn advance(&mut self) -> char {
-        let ch = self.source[self.current];
-        self.current += 1;
+        let ch = self.peek();
+        self.current += ch.len_utf8();
+        self.current_column += 1;
         ch
     }
 
     // Looks at current char but does not advance.
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
+        match self.source.as_bytes().get(self.current) {
+            None => '\0',
+            Some(byte) if byte.is_ascii() => *byte as char,
+            Some(_) => self.source[self.current..]
+                .chars()
+                .next()
+                .expect("the scanner stops on char boundaries"),
         }
-        self.source[self.current]
     }
 
     fn peek_next(&self) -> Option<char> {
         if self.is_at_end() {
             return None;
         }
-        Some(self.source[self.current + 1])
+        let next = self.current + self.peek().len_utf8();
+        self.source[next..].chars().next()
     }
 
     fn match_(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-
-        if self.source[self.current] != expected {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
-        return true;
+        self.advance();
+        true
     }
 
     fn is_at_end(&self) -> bool {
-        return self.current == self.source.len();
+        self.current == self.source.len()
     }
 
-    fn string(&mut self) -> Token {
+    fn string(&mut self) -> Token<'src> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            return self.make_error("Unterminated string.".to_string());
+            return self.make_error(ScanError {
+                code: "E0002",
+                message: "Unterminated string.",
+                label: "string starts here",
+                help: Some("add a closing '\"'"),
+            });
         }
 
         self.advance();
         self.make_token(TokenType::String)
     }
 
-    fn number(&mut self) -> Token {
+    fn number(&mut self) -> Token<'src> {
         let radix = 10;
         while self.peek().is_digit(radix) {
             self.advance();
@@ -239,10 +299,10 @@ impl Scanner {
             }
         }
 
-        return self.make_token(TokenType::Number);
+        self.make_token(TokenType::Number)
     }
 
-    fn identifier(&mut self) -> Token {
+    fn identifier(&mut self) -> Token<'src> {
         // One needs to check that the identifier starts with alpha before calling this function
         while self.peek().is_alphabetic() || self.peek() == '_' || self.peek().is_digit(RADIX) {
             self.advance();
@@ -252,30 +312,36 @@ impl Scanner {
     }
 
     fn identifier_type(&self) -> TokenType {
+        let bytes = self.source.as_bytes();
         let got_next = self.current - self.start > 1;
-        match (self.source[self.start], got_next) {
-            ('a', _) => self.check_keyword("nd", 1, 2, TokenType::And),
-            ('c', _) => self.check_keyword("lass", 1, 4, TokenType::Class),
-            ('e', _) => self.check_keyword("lse", 1, 3, TokenType::Else),
-            ('f', true) => match self.source[self.start + 1] {
-                'a' => self.check_keyword("lse", 2, 3, TokenType::False),
-                'o' => self.check_keyword("r", 2, 1, TokenType::For),
-                'u' => self.check_keyword("n", 2, 1, TokenType::Fun),
+        match (bytes[self.start], got_next) {
+            (b'a', _) => self.check_keyword("nd", 1, 2, TokenType::And),
+            (b'b', _) => self.check_keyword("reak", 1, 4, TokenType::Break),
+            (b'c', true) => match bytes[self.start + 1] {
+                b'l' => self.check_keyword("ass", 2, 3, TokenType::Class),
+                b'o' => self.check_keyword("ntinue", 2, 6, TokenType::Continue),
+                _ => TokenType::Identifier,
+            },
+            (b'e', _) => self.check_keyword("lse", 1, 3, TokenType::Else),
+            (b'f', true) => match bytes[self.start + 1] {
+                b'a' => self.check_keyword("lse", 2, 3, TokenType::False),
+                b'o' => self.check_keyword("r", 2, 1, TokenType::For),
+                b'u' => self.check_keyword("n", 2, 1, TokenType::Fun),
                 _ => TokenType::Identifier,
             },
-            ('i', _) => self.check_keyword("f", 1, 1, TokenType::If),
-            ('n', _) => self.check_keyword("il", 1, 2, TokenType::Nil),
-            ('o', _) => self.check_keyword("r", 1, 1, TokenType::Or),
-            ('p', _) => self.check_keyword("rint", 1, 4, TokenType::Print),
-            ('r', _) => self.check_keyword("eturn", 1, 5, TokenType::Return),
-            ('s', _) => self.check_keyword("uper", 1, 4, TokenType::Super),
-            ('t', _) => match self.source[self.start + 1] {
-                'h' => self.check_keyword("is", 2, 2, TokenType::This),
-                'r' => self.check_keyword("ue", 2, 2, TokenType::True),
+            (b'i', _) => self.check_keyword("f", 1, 1, TokenType::If),
+            (b'n', _) => self.check_keyword("il", 1, 2, TokenType::Nil),
+            (b'o', _) => self.check_keyword("r", 1, 1, TokenType::Or),
+            (b'p', _) => self.check_keyword("rint", 1, 4, TokenType::Print),
+            (b'r', _) => self.check_keyword("eturn", 1, 5, TokenType::Return),
+            (b's', _) => self.check_keyword("uper", 1, 4, TokenType::Super),
+            (b't', true) => match bytes[self.start + 1] {
+                b'h' => self.check_keyword("is", 2, 2, TokenType::This),
+                b'r' => self.check_keyword("ue", 2, 2, TokenType::True),
                 _ => TokenType::Identifier,
             },
-            ('v', _) => self.check_keyword("ar", 1, 2, TokenType::Var),
-            ('w', _) => self.check_keyword("hile", 1, 4, TokenType::While),
+            (b'v', _) => self.check_keyword("ar", 1, 2, TokenType::Var),
+            (b'w', _) => self.check_keyword("hile", 1, 4, TokenType::While),
 
             _ => TokenType::Identifier,
         }
@@ -288,172 +354,211 @@ impl Scanner {
         length: usize,
         tok_type: TokenType,
     ) -> TokenType {
-        if compare(&self.source, postfix, self.start + offset, length) {
+        // The keyword must span the whole identifier, `breaks` is not `break`.
+        if self.current - self.start == offset + length
+            && compare(self.source.as_bytes(), postfix, self.start + offset, length)
+        {
             return tok_type;
         }
-        return TokenType::Identifier;
+        TokenType::Identifier
     }
 
-    fn make_token(&self, type_: TokenType) -> Token {
-        return Token {
+    fn make_token(&self, type_: TokenType) -> Token<'src> {
+        Token {
             type_,
-            lexeme: self.source[self.start..self.current]
-                .iter()
-                .collect::<String>(),
-            line: self.line,
-        };
+            lexeme: &self.source[self.start..self.current],
+            span: Span {
+                start: self.start,
+                end: self.current,
+            },
+            line: self.start_line,
+            column: self.column,
+            error: None,
+        }
     }
-    fn make_error(&self, msg: String) -> Token {
-        return Token {
-            type_: TokenType::Error,
-            lexeme: msg,
-            line: self.line,
-        };
+
+    fn make_error(&self, error: ScanError) -> Token<'src> {
+        Token {
+            error: Some(error),
+            ..self.make_token(TokenType::Error)
+        }
     }
 }
 
-pub fn compare(original: &Vec<char>, postfix: &str, start: usize, length: usize) -> bool {
+pub fn compare(original: &[u8], postfix: &str, start: usize, length: usize) -> bool {
     if start + length > original.len() {
         return false;
     }
-    // assumes that original and postfix have same length
-    let mut eq = true;
-    for (a, b) in original[start..start + length].iter().zip(postfix.chars()) {
-        println!("{} {}", a, b);
-        eq &= *a == b
-    }
-    println!("out");
-    eq
+    original[start..start + length] == *postfix.as_bytes()
 }
 
+#[cfg(test)]
 mod test_scanner {
-    use crate::scanner::{compare, Scanner, Token, TokenType};
+    use crate::scanner::{compare, Scanner, Span, Token, TokenType};
 
     #[test]
     fn test_compare() {
-        let my_vec = "test string".chars().collect::<Vec<char>>();
-        assert_eq!(compare(&my_vec, "string", 5, 6), true)
+        assert!(compare("test string".as_bytes(), "string", 5, 6))
     }
 
     #[test]
     fn test_empty_string_scan() {
-        let mut scnnr = Scanner::new(&"");
+        let mut scnnr = Scanner::new("");
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::Eof,
-                lexeme: "".to_string(),
-                line: 1
+                lexeme: "",
+                span: Span { start: 0, end: 0 },
+                line: 1,
+                column: 1,
+                error: None,
             }
         )
     }
 
     #[test]
     fn test_parens_scan() {
-        let mut scnnr = Scanner::new(&"(  (  )    )");
+        let mut scnnr = Scanner::new("(  (  )    )");
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::LeftParen,
-                lexeme: "(".to_string(),
-                line: 1
+                lexeme: "(",
+                span: Span { start: 0, end: 1 },
+                line: 1,
+                column: 1,
+                error: None,
             }
         );
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::LeftParen,
-                lexeme: "(".to_string(),
-                line: 1
+                lexeme: "(",
+                span: Span { start: 3, end: 4 },
+                line: 1,
+                column: 4,
+                error: None,
             }
         );
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::RightParen,
-                lexeme: ")".to_string(),
-                line: 1
+                lexeme: ")",
+                span: Span { start: 6, end: 7 },
+                line: 1,
+                column: 7,
+                error: None,
             }
         );
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::RightParen,
-                lexeme: ")".to_string(),
-                line: 1
+                lexeme: ")",
+                span: Span { start: 11, end: 12 },
+                line: 1,
+                column: 12,
+                error: None,
             }
         );
     }
     #[test]
     fn test_lookahead_scan() {
-        let mut scnnr = Scanner::new(&"== =!= >= \n <= ");
+        let mut scnnr = Scanner::new("== =!= >= \n <= ");
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::EqualEqual,
-                lexeme: "==".to_string(),
-                line: 1
+                lexeme: "==",
+                span: Span { start: 0, end: 2 },
+                line: 1,
+                column: 1,
+                error: None,
             }
         );
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::Equal,
-                lexeme: "=".to_string(),
-                line: 1
+                lexeme: "=",
+                span: Span { start: 3, end: 4 },
+                line: 1,
+                column: 4,
+                error: None,
             }
         );
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::BangEqual,
-                lexeme: "!=".to_string(),
-                line: 1
+                lexeme: "!=",
+                span: Span { start: 4, end: 6 },
+                line: 1,
+                column: 5,
+                error: None,
             }
         );
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::GreaterEqual,
-                lexeme: ">=".to_string(),
-                line: 1
+                lexeme: ">=",
+                span: Span { start: 7, end: 9 },
+                line: 1,
+                column: 8,
+                error: None,
             }
         );
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::LessEqual,
-                lexeme: "<=".to_string(),
-                line: 2
+                lexeme: "<=",
+                span: Span { start: 12, end: 14 },
+                line: 2,
+                column: 2,
+                error: None,
             }
         )
     }
 
     #[test]
     fn test_comment() {
-        let mut scnnr = Scanner::new(&"== / // this is a comment \n <= ");
+        let mut scnnr = Scanner::new("== / // this is a comment \n <= ");
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::EqualEqual,
-                lexeme: "==".to_string(),
-                line: 1
+                lexeme: "==",
+                span: Span { start: 0, end: 2 },
+                line: 1,
+                column: 1,
+                error: None,
             }
         );
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::Slash,
-                lexeme: "/".to_string(),
-                line: 1
+                lexeme: "/",
+                span: Span { start: 3, end: 4 },
+                line: 1,
+                column: 4,
+                error: None,
             }
         );
         assert_eq!(
             scnnr.scan_token(),
             Token {
                 type_: TokenType::LessEqual,
-                lexeme: "<=".to_string(),
-                line: 2
+                lexeme: "<=",
+                span: Span { start: 28, end: 30 },
+                line: 2,
+                column: 2,
+                error: None,
             }
         )
     }
@@ -465,8 +570,11 @@ mod test_scanner {
             scnnr.scan_token(),
             Token {
                 type_: TokenType::String,
-                lexeme: "\"...\n...\n\"".to_string(),
-                line: 3
+                lexeme: "\"...\n...\n\"",
+                span: Span { start: 0, end: 10 },
+                line: 1,
+                column: 1,
+                error: None,
             }
         )
     }
@@ -478,16 +586,22 @@ mod test_scanner {
             s.scan_token(),
             Token {
                 type_: TokenType::Number,
-                lexeme: "1.2".to_string(),
-                line: 1
+                lexeme: "1.2",
+                span: Span { start: 0, end: 3 },
+                line: 1,
+                column: 1,
+                error: None,
             }
         );
         assert_eq!(
             s.scan_token(),
             Token {
                 type_: TokenType::Number,
-                lexeme: "345.6".to_string(),
-                line: 1
+                lexeme: "345.6",
+                span: Span { start: 4, end: 9 },
+                line: 1,
+                column: 5,
+                error: None,
             }
         )
     }
@@ -499,16 +613,22 @@ mod test_scanner {
             s.scan_token(),
             Token {
                 type_: TokenType::While,
-                lexeme: "while".to_string(),
-                line: 1
+                lexeme: "while",
+                span: Span { start: 0, end: 5 },
+                line: 1,
+                column: 1,
+                error: None,
             }
         );
         assert_eq!(
             s.scan_token(),
             Token {
                 type_: TokenType::True,
-                lexeme: "true".to_string(),
+                lexeme: "true",
+                span: Span { start: 6, end: 10 },
                 line: 1,
+                column: 7,
+                error: None,
             }
         );
         s.scan_token();
@@ -516,9 +636,58 @@ mod test_scanner {
             s.scan_token(),
             Token {
                 type_: TokenType::Print,
-                lexeme: "print".to_string(),
+                lexeme: "print",
+                span: Span { start: 13, end: 18 },
                 line: 1,
+                column: 14,
+                error: None,
             }
         );
     }
+
+    #[test]
+    fn test_spans() {
+        let source = "var é = \"ü\";";
+        let mut s = Scanner::new(source);
+        s.scan_token();
+        let name = s.scan_token();
+        assert_eq!(name.span, Span { start: 4, end: 6 });
+        assert_eq!((name.lexeme, name.column), ("é", 5));
+        s.scan_token();
+        let string = s.scan_token();
+        assert_eq!(string.span, Span { start: 9, end: 13 });
+        assert_eq!((string.lexeme, string.column), ("\"ü\"", 9));
+        let error = Scanner::new("\n  @").scan_token();
+        let diagnostic = error.diagnostic().unwrap();
+        assert_eq!(diagnostic.message, "Unexpected charecter.");
+        assert_eq!(diagnostic.position("\n  @"), Some((2, 3)));
+        assert_eq!((error.line, error.column, error.lexeme), (2, 3, "@"));
+    }
+
+    #[test]
+    fn test_multibyte_lexemes() {
+        let mut s = Scanner::new("naïve \"日本\" §");
+        assert_eq!(s.scan_token().lexeme, "naïve");
+        let string = s.scan_token();
+        assert_eq!(
+            (string.type_, string.lexeme),
+            (TokenType::String, "\"日本\"")
+        );
+        let error = s.scan_token();
+        assert_eq!(
+            (error.type_, error.lexeme, error.column),
+            (TokenType::Error, "§", 12)
+        );
+        assert_eq!(s.scan_token().type_, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_parse_loop_keywords() {
+        let mut s = Scanner::new("break continue class c breaks");
+        assert_eq!(s.scan_token().type_, TokenType::Break);
+        assert_eq!(s.scan_token().type_, TokenType::Continue);
+        assert_eq!(s.scan_token().type_, TokenType::Class);
+        assert_eq!(s.scan_token().type_, TokenType::Identifier);
+        assert_eq!(s.scan_token().type_, TokenType::Identifier);
+    }
 }
@@ -1,73 +1,1266 @@
-use crate::common::{Chunk, OpCode, Value};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
 
-pub struct VM {
+use crate::common::{read_u24, ObjKind, ObjRef, ObjUpvalue, OpCode, Value};
+use crate::compiler::{Compiler, MAX_ERRORS};
+use crate::diagnostics::{line_and_column, Diagnostic, ErrorFormat};
+use crate::errors::LoxError;
+use crate::memory::{GcConfig, GcStats, Heap};
+
+pub struct Config {
+    // How deep calls can nest before reporting a stack overflow.
+    pub frames_max: usize,
+    pub gc: GcConfig,
+    pub error_format: ErrorFormat,
+    // Compile errors reported before giving up on the source.
+    pub max_errors: NonZeroUsize,
+    // Dumps the stack and disassembles every instruction before running it.
+    #[cfg(feature = "trace")]
+    pub trace_execution: bool,
+    // Lists the bytecode of every compiled function before running the script.
+    #[cfg(feature = "trace")]
+    pub print_code: bool,
+    // Where traces and listings are written to.
+    #[cfg(feature = "trace")]
+    pub trace_output: Box<dyn Write>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            frames_max: 64,
+            gc: GcConfig::default(),
+            error_format: ErrorFormat::default(),
+            max_errors: MAX_ERRORS,
+            #[cfg(feature = "trace")]
+            trace_execution: false,
+            #[cfg(feature = "trace")]
+            print_code: false,
+            #[cfg(feature = "trace")]
+            trace_output: Box::new(io::stderr()),
+        }
+    }
+}
+
+// A function invocation in progress.
+struct CallFrame {
+    closure: ObjRef,
     ip: usize,
-    stack: Vec<Value>,
+    // Index of the first stack slot the function can use, it holds the callee.
+    slots: usize,
 }
 
-pub enum InterpretResult {
-    InterpretOk,
-    InterpretCompileError,
-    InterpretRuntimeError,
+pub struct VM {
+    config: Config,
+    frames: Vec<CallFrame>,
+    stack: Vec<Value>,
+    heap: Heap,
+    // Keyed by the interned variable name.
+    globals: HashMap<ObjRef, Value>,
+    // Upvalues still pointing into the stack, at most one per stack slot.
+    open_upvalues: Vec<ObjRef>,
+    // Interned name of class initializers.
+    init_string: ObjRef,
+    // Where `print` statements write to.
+    out: Box<dyn Write>,
 }
 
 impl VM {
-    pub fn new() -> Self {
+    pub fn new(config: Config) -> Self {
+        Self::with_config(config, Box::new(io::stdout()))
+    }
+
+    pub fn with_config(config: Config, out: Box<dyn Write>) -> Self {
+        let mut heap = Heap::with_config(config.gc);
+        let init_string = heap.copy_string("init");
         VM {
-            ip: 0,
+            config,
+            frames: vec![],
             stack: vec![],
+            heap,
+            globals: HashMap::new(),
+            open_upvalues: vec![],
+            init_string,
+            out,
+        }
+    }
+
+    // Errors are reported as they happen, the first one is returned. `file` is only used to
+    // name the source in the reports.
+    pub fn interpret(&mut self, file: &str, source: &str) -> Result<(), LoxError> {
+        // Objects referenced by the vm must survive collections started by the compiler.
+        let globals = &self.globals;
+        let init_string = self.init_string;
+        let vm_roots = move |heap: &mut Heap| {
+            heap.mark_table(globals);
+            heap.mark_object(init_string);
+        };
+        let compiler = Compiler::new(source, &mut self.heap, &vm_roots);
+        let function = match compiler.max_errors(self.config.max_errors).compile() {
+            Ok(function) => function,
+            Err(errors) => {
+                for error in &errors {
+                    self.report(&error.diagnostic(), file, source);
+                }
+                return Err(errors
+                    .into_iter()
+                    .next()
+                    .expect("compiling only fails with an error"));
+            }
+        };
+        #[cfg(feature = "trace")]
+        if self.config.print_code {
+            if let Err(error) = self.print_code(function) {
+                return Err(self.runtime_error(error, file));
+            }
+        }
+
+        self.push(Value::obj(function));
+        let closure = self.heap.alloc_closure(function, vec![]);
+        self.pop();
+        self.push(Value::obj(closure));
+        let result = self
+            .call(closure, 0)
+            .map_err(LoxError::from)
+            .and_then(|()| self.execute());
+        result.map_err(|error| self.runtime_error(error, file))
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames
+            .last()
+            .expect("code only runs inside a call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("code only runs inside a call frame")
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let function = frame.closure.as_closure().function;
+        let byte = function.as_function().chunk.read(frame.ip);
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_opcode(&mut self) -> Result<OpCode, LoxError> {
+        OpCode::try_from(self.read_byte())
+    }
+
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte();
+        let low = self.read_byte();
+        u16::from_be_bytes([high, low])
+    }
+
+    // Instructions prefixed with OpWide have a 24 bit constant index.
+    fn read_constant(&mut self, wide: bool) -> Value {
+        let constant_offest = if wide {
+            read_u24(&[self.read_byte(), self.read_byte(), self.read_byte()])
+        } else {
+            self.read_byte() as usize
+        };
+        let function = self.frame().closure.as_closure().function;
+        *function.as_function().chunk.get_constant(constant_offest)
+    }
+
+    fn read_string(&mut self, wide: bool) -> ObjRef {
+        match self.read_constant(wide).as_obj() {
+            Some(name) => name,
+            None => unreachable!("the compiler only emits string constants for names"),
         }
     }
 
-    pub fn interpret(&mut self, chunk: &Chunk) -> InterpretResult {
-        self.ip = 0;
-        self.run(chunk)
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack
+            .pop()
+            .expect("the compiler never emits code that underflows the stack")
     }
 
-    fn read_byte(&mut self, chunk: &Chunk) -> OpCode {
-        let opcode: OpCode = chunk.read(self.ip).into();
-        self.ip += 1;
-        opcode
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
     }
 
-    fn run(&mut self, chunk: &Chunk) -> InterpretResult {
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), Diagnostic> {
+        let Some(obj) = callee.as_obj() else {
+            return Err(Diagnostic::error(
+                "E0203",
+                "Can only call functions and classes.",
+            ));
+        };
+        match &obj.kind {
+            ObjKind::BoundMethod(bound) => {
+                // The receiver takes the slot of the callee, it becomes `this`.
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = bound.receiver;
+                self.call(bound.method, arg_count)
+            }
+            ObjKind::Class(class) => {
+                let instance = self.heap.alloc_instance(obj);
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = Value::obj(instance);
+                match class.methods.get(&self.init_string).and_then(Value::as_obj) {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    _ if arg_count != 0 => Err(Diagnostic::error(
+                        "E0204",
+                        format!("Expected 0 arguments but got {}.", arg_count),
+                    )),
+                    _ => Ok(()),
+                }
+            }
+            ObjKind::Closure(_) => self.call(obj, arg_count),
+            _ => Err(Diagnostic::error(
+                "E0203",
+                "Can only call functions and classes.",
+            )),
+        }
+    }
+
+    fn invoke(&mut self, name: ObjRef, arg_count: u8) -> Result<(), Diagnostic> {
+        let receiver = *self.peek(arg_count as usize);
+        let Some(instance) = receiver.as_obj() else {
+            return Err(Diagnostic::error("E0200", "Only instances have methods."));
+        };
+        let ObjKind::Instance(instance) = &instance.kind else {
+            return Err(Diagnostic::error("E0200", "Only instances have methods."));
+        };
+
+        // A field holding a function shadows the method.
+        if let Some(value) = instance.fields.get(&name) {
+            let slot = self.stack.len() - arg_count as usize - 1;
+            self.stack[slot] = *value;
+            return self.call_value(*value, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        arg_count: u8,
+    ) -> Result<(), Diagnostic> {
+        match class.as_class().methods.get(&name).and_then(Value::as_obj) {
+            Some(method) => self.call(method, arg_count),
+            _ => Err(Diagnostic::error(
+                "E0202",
+                format!("Undefined property '{}'.", name),
+            )),
+        }
+    }
+
+    // Replaces the instance on top of the stack with the method bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), Diagnostic> {
+        let Some(method) = class.as_class().methods.get(&name).and_then(Value::as_obj) else {
+            return Err(Diagnostic::error(
+                "E0202",
+                format!("Undefined property '{}'.", name),
+            ));
+        };
+
+        let bound = self.heap.alloc_bound_method(*self.peek(0), method);
+        self.pop();
+        self.push(Value::obj(bound));
+        Ok(())
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: u8) -> Result<(), Diagnostic> {
+        let arity = closure.as_closure().function.as_function().arity;
+        if arg_count as usize != arity {
+            return Err(Diagnostic::error(
+                "E0204",
+                format!("Expected {} arguments but got {}.", arity, arg_count),
+            ));
+        }
+
+        if self.frames.len() == self.config.frames_max {
+            return Err(
+                Diagnostic::error("E0205", "Stack overflow.").with_note(format!(
+                    "calls can nest at most {} deep",
+                    self.config.frames_max
+                )),
+            );
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - arg_count as usize - 1,
+        });
+        Ok(())
+    }
+
+    // Returns the upvalue for the stack slot, reusing it if the slot is captured already
+    // so closures share the variable.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let existing = self.open_upvalues.iter().find(
+            |upvalue| matches!(upvalue.as_upvalue(), ObjUpvalue::Open(open) if *open == slot),
+        );
+        if let Some(upvalue) = existing {
+            return *upvalue;
+        }
+
+        let upvalue = self.heap.alloc_upvalue(slot);
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    // Moves the values of every slot from `last` upwards out of the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        let (stack, heap) = (&self.stack, &mut self.heap);
+        self.open_upvalues.retain_mut(|upvalue| {
+            let ObjUpvalue::Open(slot) = *upvalue.as_upvalue() else {
+                unreachable!("only open upvalues are tracked")
+            };
+            if slot < last {
+                return true;
+            }
+            // The value leaves the stack, which is rescanned when marking ends.
+            heap.write_barrier(stack[slot]);
+            *upvalue.as_upvalue_mut() = ObjUpvalue::Closed(stack[slot]);
+            false
+        });
+    }
+
+    fn binary_op(&mut self, op: fn(f64, f64) -> Value) -> Result<(), Diagnostic> {
+        match (self.peek(1).as_number(), self.peek(0).as_number()) {
+            (Some(a), Some(b)) => {
+                self.pop();
+                self.pop();
+                self.push(op(a, b));
+                Ok(())
+            }
+            _ => Err(Diagnostic::error("E0200", "Operands must be numbers.")),
+        }
+    }
+
+    fn concatenate(&mut self) {
+        let b = self.pop();
+        let a = self.pop();
+        let (Some(a), Some(b)) = (a.as_obj(), b.as_obj()) else {
+            unreachable!("concatenate is only called with two strings")
+        };
+        let chars = a.as_string().chars.clone() + &b.as_string().chars;
+        let result = self.heap.take_string(chars);
+        self.push(Value::obj(result));
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        self.heap.stats()
+    }
+
+    fn collect_garbage(&mut self) {
+        let (stack, frames, open_upvalues) = (&self.stack, &self.frames, &self.open_upvalues);
+        let (globals, init_string) = (&self.globals, self.init_string);
+        self.heap.collect_garbage(&|heap| {
+            for value in stack {
+                heap.mark_value(*value);
+            }
+            for frame in frames {
+                heap.mark_object(frame.closure);
+            }
+            for upvalue in open_upvalues {
+                heap.mark_object(*upvalue);
+            }
+            heap.mark_table(globals);
+            heap.mark_object(init_string);
+        });
+    }
+
+    // Disassembles the function and every function nested in it.
+    #[cfg(feature = "trace")]
+    fn print_code(&mut self, function: ObjRef) -> Result<(), LoxError> {
+        let function = function.as_function();
+        let name = match function.name {
+            Some(name) => name.to_string(),
+            None => "<script>".to_string(),
+        };
+        function
+            .chunk
+            .disassemble_chunk(&name, self.config.trace_output.as_mut())?;
+        for constant in function.chunk.constants() {
+            match constant.as_obj() {
+                Some(nested) if matches!(nested.kind, ObjKind::Function(_)) => {
+                    self.print_code(nested)?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Prints the stack followed by the instruction about to run.
+    #[cfg(feature = "trace")]
+    fn trace_instruction(&mut self) -> Result<(), LoxError> {
+        write!(self.config.trace_output, "          ")?;
+        for value in &self.stack {
+            write!(self.config.trace_output, "[ {} ]", value)?;
+        }
+        writeln!(self.config.trace_output)?;
+        let frame = self.frame();
+        let (function, ip) = (frame.closure.as_closure().function, frame.ip);
+        function
+            .as_function()
+            .chunk
+            .disassemble_instruction(ip, self.config.trace_output.as_mut())?;
+        Ok(())
+    }
+
+    // Prints the diagnostic to stderr in the configured error format.
+    pub fn report(&self, diagnostic: &Diagnostic, file: &str, source: &str) {
+        let report = diagnostic.format(self.config.error_format, file, source);
+        match self.config.error_format {
+            // Reports are separated by a blank line.
+            ErrorFormat::Human => eprintln!("{}\n", report),
+            ErrorFormat::Json => eprintln!("{}", report),
+        }
+    }
+
+    // Points the error at the failing instruction and lists the calls leading to it.
+    fn runtime_error(&mut self, error: LoxError, file: &str) -> LoxError {
+        let mut diagnostic = error.diagnostic();
+        let mut source = None;
+        for frame in self.frames.iter().rev() {
+            let function = frame.closure.as_closure().function;
+            let function = function.as_function();
+            // the ip already moved past the failing instruction
+            let span = function.chunk.get_span(frame.ip - 1);
+            let (line, column) = line_and_column(&function.source, span.start);
+            if source.is_none() {
+                diagnostic = diagnostic.with_primary(span, "");
+                source = Some(function.source.clone());
+            }
+            diagnostic = match function.name {
+                Some(name) => {
+                    diagnostic.with_note(format!("in {}() at line {}:{}", name, line, column))
+                }
+                None => diagnostic.with_note(format!("in script at line {}:{}", line, column)),
+            };
+        }
+        self.report(&diagnostic, file, source.as_deref().unwrap_or(""));
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        match error {
+            // Keep the position for callers that look at the error.
+            LoxError::Runtime(_) => LoxError::Runtime(diagnostic),
+            error => error,
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), LoxError> {
+        // Set by OpWide for the instruction following it.
+        let mut widen = false;
         loop {
-            chunk.disassemble_instruction(self.ip);
-            match self.read_byte(chunk) {
+            // Between two instructions every live object is reachable from the roots.
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+
+            // The prefix was traced together with the instruction it widens.
+            #[cfg(feature = "trace")]
+            if self.config.trace_execution && !widen {
+                self.trace_instruction()?;
+            }
+            let wide = std::mem::take(&mut widen);
+            match self.read_opcode()? {
+                OpCode::OpWide => widen = true,
                 OpCode::OpAdd => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a + b);
+                    if self.peek(0).is_string() && self.peek(1).is_string() {
+                        self.concatenate();
+                    } else if self.peek(0).as_number().is_some()
+                        && self.peek(1).as_number().is_some()
+                    {
+                        self.binary_op(|a, b| Value::number(a + b))?;
+                    } else {
+                        return Err(Diagnostic::error(
+                            "E0200",
+                            "Operands must be two numbers or two strings.",
+                        )
+                        .into());
+                    }
                 }
-                OpCode::OpSubtract => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a - b);
+                OpCode::OpSubtract => self.binary_op(|a, b| Value::number(a - b))?,
+                OpCode::OpMultiply => self.binary_op(|a, b| Value::number(a * b))?,
+                OpCode::OpDevide => self.binary_op(|a, b| Value::number(a / b))?,
+                OpCode::OpGreater => self.binary_op(|a, b| Value::bool(a > b))?,
+                OpCode::OpLess => self.binary_op(|a, b| Value::bool(a < b))?,
+                OpCode::OpNegate => {
+                    let Some(val) = self.peek(0).as_number() else {
+                        return Err(Diagnostic::error("E0200", "Operand must be a number.").into());
+                    };
+                    self.pop();
+                    self.push(Value::number(-val))
                 }
-                OpCode::OpMultiply => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a * b);
+                OpCode::OpNot => {
+                    let val = self.pop();
+                    self.push(Value::bool(val.is_falsey()))
                 }
-                OpCode::OpDevide => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a / b);
+                OpCode::OpEqual => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::bool(a == b));
                 }
-                OpCode::OpNegate => {
-                    let val = self.stack.pop().unwrap();
-                    self.stack.push(-val)
+                OpCode::OpNil => self.push(Value::nil()),
+                OpCode::OpTrue => self.push(Value::bool(true)),
+                OpCode::OpFalse => self.push(Value::bool(false)),
+                OpCode::OpPop => {
+                    self.pop();
+                }
+                OpCode::OpGetLocal => {
+                    let slot = self.read_byte() as usize;
+                    self.push(self.stack[self.frame().slots + slot]);
+                }
+                OpCode::OpSetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let slots = self.frame().slots;
+                    self.stack[slots + slot] = *self.peek(0);
+                }
+                OpCode::OpGetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.as_closure().upvalues[slot];
+                    let value = match *upvalue.as_upvalue() {
+                        ObjUpvalue::Open(location) => self.stack[location],
+                        ObjUpvalue::Closed(value) => value,
+                    };
+                    self.push(value);
+                }
+                OpCode::OpSetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let mut upvalue = self.frame().closure.as_closure().upvalues[slot];
+                    let value = *self.peek(0);
+                    self.heap.write_barrier(value);
+                    match upvalue.as_upvalue_mut() {
+                        ObjUpvalue::Open(location) => self.stack[*location] = value,
+                        ObjUpvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::OpGetProperty => {
+                    if !self.peek(0).is_instance() {
+                        return Err(
+                            Diagnostic::error("E0200", "Only instances have properties.").into(),
+                        );
+                    }
+                    let Some(instance) = self.peek(0).as_obj() else {
+                        unreachable!("checked to be an instance")
+                    };
+                    let instance = instance.as_instance();
+                    let name = self.read_string(wide);
+
+                    if let Some(value) = instance.fields.get(&name) {
+                        let value = *value;
+                        self.pop(); // Instance.
+                        self.push(value);
+                    } else {
+                        self.bind_method(instance.class, name)?;
+                    }
+                }
+                OpCode::OpSetProperty => {
+                    if !self.peek(1).is_instance() {
+                        return Err(
+                            Diagnostic::error("E0200", "Only instances have fields.").into()
+                        );
+                    }
+                    let Some(mut instance) = self.peek(1).as_obj() else {
+                        unreachable!("checked to be an instance")
+                    };
+                    let name = self.read_string(wide);
+                    let value = self.pop();
+                    self.heap.write_barrier(Value::obj(name));
+                    self.heap.write_barrier(value);
+                    instance.as_instance_mut().fields.insert(name, value);
+                    self.pop(); // Instance.
+                    self.push(value);
+                }
+                OpCode::OpGetSuper => {
+                    let name = self.read_string(wide);
+                    let Some(superclass) = self.pop().as_obj() else {
+                        unreachable!("super always resolves to a class")
+                    };
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::OpGetGlobal => {
+                    let name = self.read_string(wide);
+                    let Some(value) = self.globals.get(&name) else {
+                        return Err(Diagnostic::error(
+                            "E0201",
+                            format!("Undefined variable '{}'.", name),
+                        )
+                        .into());
+                    };
+                    self.push(*value);
+                }
+                OpCode::OpDefineGlobal => {
+                    let name = self.read_string(wide);
+                    let value = self.pop();
+                    self.heap.write_barrier(Value::obj(name));
+                    self.heap.write_barrier(value);
+                    self.globals.insert(name, value);
+                }
+                OpCode::OpSetGlobal => {
+                    let name = self.read_string(wide);
+                    let value = *self.peek(0);
+                    self.heap.write_barrier(value);
+                    // Assignment never creates a variable.
+                    if self.globals.insert(name, value).is_none() {
+                        self.globals.remove(&name);
+                        return Err(Diagnostic::error(
+                            "E0201",
+                            format!("Undefined variable '{}'.", name),
+                        )
+                        .with_help(format!(
+                            "assignment does not declare variables, use 'var {} = ...;'",
+                            name
+                        ))
+                        .into());
+                    }
                 }
                 OpCode::OpConstant => {
-                    let constant_offest = self.read_byte(chunk);
-                    let value = chunk.get_constant(constant_offest as usize);
-                    self.stack.push(*value);
+                    let value = self.read_constant(wide);
+                    self.push(value);
+                }
+                OpCode::OpConstantLong => {
+                    let value = self.read_constant(true);
+                    self.push(value);
+                }
+                OpCode::OpPrint => {
+                    let value = self.pop();
+                    writeln!(self.out, "{}", value)?;
+                }
+                OpCode::OpJump => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                OpCode::OpJumpIfFalse => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::OpLoop => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OpCode::OpCall => {
+                    let arg_count = self.read_byte();
+                    self.call_value(*self.peek(arg_count as usize), arg_count)?;
+                }
+                OpCode::OpInvoke => {
+                    let method = self.read_string(wide);
+                    let arg_count = self.read_byte();
+                    self.invoke(method, arg_count)?;
+                }
+                OpCode::OpSuperInvoke => {
+                    let method = self.read_string(wide);
+                    let arg_count = self.read_byte();
+                    let Some(superclass) = self.pop().as_obj() else {
+                        unreachable!("super always resolves to a class")
+                    };
+                    self.invoke_from_class(superclass, method, arg_count)?;
+                }
+                OpCode::OpClosure => {
+                    let Some(function) = self.read_constant(wide).as_obj() else {
+                        unreachable!("closures are only created from function constants")
+                    };
+                    let upvalue_count = function.as_function().upvalue_count;
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame().closure.as_closure().upvalues[index]);
+                        }
+                    }
+                    let closure = self.heap.alloc_closure(function, upvalues);
+                    self.push(Value::obj(closure));
+                }
+                OpCode::OpClass => {
+                    let name = self.read_string(wide);
+                    let class = self.heap.alloc_class(name);
+                    self.push(Value::obj(class));
+                }
+                OpCode::OpInherit => {
+                    let superclass = match self.peek(1).as_obj() {
+                        Some(obj) if matches!(obj.kind, ObjKind::Class(_)) => obj,
+                        _ => {
+                            return Err(
+                                Diagnostic::error("E0200", "Superclass must be a class.").into()
+                            )
+                        }
+                    };
+                    let Some(mut subclass) = self.peek(0).as_obj() else {
+                        unreachable!("the subclass is loaded right after its superclass")
+                    };
+                    // Methods are copied down, overriding ones are defined after this.
+                    let methods = superclass.as_class().methods.clone();
+                    for (name, method) in &methods {
+                        self.heap.write_barrier(Value::obj(*name));
+                        self.heap.write_barrier(*method);
+                    }
+                    subclass.as_class_mut().methods.extend(methods);
+                    self.pop(); // Subclass.
+                }
+                OpCode::OpMethod => {
+                    let name = self.read_string(wide);
+                    let method = *self.peek(0);
+                    let Some(mut class) = self.peek(1).as_obj() else {
+                        unreachable!("methods are only defined right after their class")
+                    };
+                    self.heap.write_barrier(Value::obj(name));
+                    self.heap.write_barrier(method);
+                    class.as_class_mut().methods.insert(name, method);
+                    self.pop();
+                }
+                OpCode::OpCloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
                 }
                 OpCode::OpReturn => {
-                    chunk.print_value(&self.stack.pop().unwrap());
-                    return InterpretResult::InterpretOk;
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("returning from a call frame");
+                    self.close_upvalues(frame.slots);
+                    if self.frames.is_empty() {
+                        // Pop the script function itself.
+                        self.pop();
+                        return Ok(());
+                    }
+
+                    self.stack.truncate(frame.slots);
+                    self.push(result);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test_vm {
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    use crate::errors::LoxError;
+    use crate::memory::{GcConfig, GcMode};
+    use crate::vm::{Config, VM};
+
+    // Collects everything the vm prints so tests can look at it.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Errors are not comparable, tests only look at the kind of error.
+    #[derive(Debug, PartialEq, Eq)]
+    enum Outcome {
+        Ok,
+        CompileError,
+        RuntimeError,
+    }
+
+    fn outcome(result: Result<(), LoxError>) -> Outcome {
+        match result {
+            Ok(()) => Outcome::Ok,
+            Err(LoxError::Scan(_) | LoxError::Compile(_)) => Outcome::CompileError,
+            Err(_) => Outcome::RuntimeError,
+        }
+    }
+
+    fn run(source: &str) -> (Outcome, String) {
+        run_with_config(source, Config::default())
+    }
+
+    fn run_with_config(source: &str, config: Config) -> (Outcome, String) {
+        let output = Output::default();
+        let mut vm = VM::with_config(config, Box::new(output.clone()));
+        let result = outcome(vm.interpret("test.lox", source));
+        let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+        (result, printed)
+    }
+
+    #[test]
+    fn test_interpret_expression() {
+        assert_eq!(
+            run("print (1.2 + 3.4) / 2;"),
+            (Outcome::Ok, "2.3\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_booleans() {
+        assert_eq!(
+            run("print !(5 - 4 > 3 * 2 == !nil); print nil == false;"),
+            (Outcome::Ok, "true\nfalse\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_runtime_error() {
+        let mut vm = VM::new(Config::default());
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "1 + true;")),
+            Outcome::RuntimeError
+        );
+        assert_eq!(vm.stack.len(), 0);
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "-nil;")),
+            Outcome::RuntimeError
+        );
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "1 < false;")),
+            Outcome::RuntimeError
+        );
+        assert_eq!(outcome(vm.interpret("test.lox", "-(2 * 3);")), Outcome::Ok);
+    }
+
+    #[test]
+    fn test_interpret_strings() {
+        assert_eq!(
+            run("print \"st\" + \"ri\" + \"ng\" == \"string\"; print \"lox\";"),
+            (Outcome::Ok, "true\nlox\n".to_string())
+        );
+        assert_eq!(run("\"a\" + 1;").0, Outcome::RuntimeError);
+    }
+
+    #[test]
+    fn test_interpret_globals() {
+        assert_eq!(
+            run("var a = 1; var b; print b; b = a = a + 1; print a + b;"),
+            (Outcome::Ok, "nil\n4\n".to_string())
+        );
+        assert_eq!(run("print x;").0, Outcome::RuntimeError);
+        assert_eq!(run("x = 1;").0, Outcome::RuntimeError);
+    }
+
+    #[test]
+    fn test_interpret_globals_persist_between_calls() {
+        let mut vm = VM::new(Config::default());
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "var a = \"x\";")),
+            Outcome::Ok
+        );
+        assert_eq!(outcome(vm.interpret("test.lox", "a = a + a;")), Outcome::Ok);
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "y = a;")),
+            Outcome::RuntimeError
+        );
+        assert!(!vm.globals.contains_key(&vm.heap.copy_string("y")));
+    }
+
+    #[test]
+    fn test_interpret_locals() {
+        assert_eq!(
+            run("var a = \"global\"; { var a = \"outer\"; { var b = a; a = \"inner\"; print a + b; } print a; } print a;"),
+            (
+                Outcome::Ok,
+                "innerouter\ninner\nglobal\n".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_interpret_if_else() {
+        assert_eq!(
+            run(
+                "if (1 > 2) print \"then\"; else print \"else\"; if (nil) print 1; if (0) print 2;"
+            ),
+            (Outcome::Ok, "else\n2\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_logical_operators() {
+        assert_eq!(
+            run("print nil or \"yes\"; print 1 and 2; print false and 1; print 1 or x;"),
+            (Outcome::Ok, "yes\n2\nfalse\n1\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_loops() {
+        assert_eq!(
+            run("var i = 0; while (i < 3) { print i; i = i + 1; }"),
+            (Outcome::Ok, "0\n1\n2\n".to_string())
+        );
+        assert_eq!(
+            run("var a = 0; var b = 1; for (var i = 0; i < 6; i = i + 1) { var t = a; a = b; b = t + b; } print a;"),
+            (Outcome::Ok, "8\n".to_string())
+        );
+        assert_eq!(
+            run("var i = 3; for (; i > 0;) i = i - 1; print i;"),
+            (Outcome::Ok, "0\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_break_continue() {
+        assert_eq!(
+            run("for (var i = 0; i < 10; i = i + 1) { var j = i * 2; if (j == 2) continue; if (i == 4) break; print j; } print \"done\";"),
+            (Outcome::Ok, "0\n4\n6\ndone\n".to_string())
+        );
+        assert_eq!(
+            run("var i = 0; while (true) { var a = 1; { var b = 2; i = i + a + b; if (i > 6) break; } } print i;"),
+            (Outcome::Ok, "9\n".to_string())
+        );
+        assert_eq!(
+            run("var n = 0; for (var i = 0; i < 3; i = i + 1) { for (var j = 0; j < 3; j = j + 1) { if (j == 1) break; n = n + 1; } } print n;"),
+            (Outcome::Ok, "3\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_functions() {
+        assert_eq!(
+            run("fun add(a, b) { return a + b; } print add(1, 2); print add;"),
+            (Outcome::Ok, "3\n<fn add>\n".to_string())
+        );
+        assert_eq!(
+            run("fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); } print fib(10);"),
+            (Outcome::Ok, "55\n".to_string())
+        );
+        assert_eq!(
+            run("fun noop() {} print noop(); { var a = 1; fun f(b) { var c = 3; return b + c; } print f(a) + a; }"),
+            (Outcome::Ok, "nil\n5\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_call_errors() {
+        let mut vm = VM::new(Config::default());
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "fun f(a) {} f(1, 2);")),
+            Outcome::RuntimeError
+        );
+        assert_eq!(vm.frames.len(), 0);
+        assert_eq!(vm.stack.len(), 0);
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "\"f\"();")),
+            Outcome::RuntimeError
+        );
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "fun g() { return 1 + nil; } g();")),
+            Outcome::RuntimeError
+        );
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "return 1;")),
+            Outcome::CompileError
+        );
+    }
+
+    #[test]
+    fn test_interpret_stack_overflow() {
+        let mut vm = VM::with_config(
+            Config {
+                frames_max: 8,
+                ..Config::default()
+            },
+            Box::new(std::io::sink()),
+        );
+        assert_eq!(
+            outcome(vm.interpret(
+                "test.lox",
+                "fun f(n) { if (n > 0) return f(n - 1); return n; } print f(6);"
+            )),
+            Outcome::Ok
+        );
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "fun g(n) { return g(n + 1); } g(0);")),
+            Outcome::RuntimeError
+        );
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "f(7);")),
+            Outcome::RuntimeError
+        );
+    }
+
+    #[test]
+    fn test_interpret_closures() {
+        assert_eq!(
+            run("fun outer() { var x = \"outside\"; fun inner() { print x; } return inner; } var f = outer(); f(); print f;"),
+            (Outcome::Ok, "outside\n<fn inner>\n".to_string())
+        );
+        assert_eq!(
+            run("fun counter() { var i = 0; fun inc() { i = i + 1; return i; } return inc; } var a = counter(); var b = counter(); a(); a(); print a(); print b();"),
+            (Outcome::Ok, "3\n1\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_shared_upvalues() {
+        assert_eq!(
+            run("var get; var set; { var v = 1; fun g() { return v; } fun s(n) { v = n; } get = g; set = s; v = 2; } set(3); print get();"),
+            (Outcome::Ok, "3\n".to_string())
+        );
+        assert_eq!(
+            run("fun outer() { var x = 1; fun middle() { fun inner() { return x; } return inner; } return middle; } print outer()()();"),
+            (Outcome::Ok, "1\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_loop_closures() {
+        assert_eq!(
+            run("var fs; for (var i = 0; i < 3; i = i + 1) { var j = i; fun f() { print j; } if (i == 1) { fs = f; break; } } fs();"),
+            (Outcome::Ok, "1\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_classes() {
+        assert_eq!(
+            run("class Pair {} var p = Pair(); p.first = 1; p.second = 2; print p.first + p.second; print Pair; print p;"),
+            (
+                Outcome::Ok,
+                "3\nPair\nPair instance\n".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_interpret_methods() {
+        assert_eq!(
+            run("class Counter { init(start) { this.count = start; } inc() { this.count = this.count + 1; return this; } } var c = Counter(5); c.inc().inc(); print c.count; var m = c.inc; m(); print c.count; print m; print c.init(0).count;"),
+            (
+                Outcome::Ok,
+                "7\n8\n<fn inc>\n0\n".to_string()
+            )
+        );
+        assert_eq!(
+            run("class A { m() { fun f() { return this.x; } return f; } } var a = A(); a.x = \"captured\"; print a.m()();"),
+            (Outcome::Ok, "captured\n".to_string())
+        );
+        assert_eq!(
+            run("class A { m() { return 1; } } var a = A(); fun g() { return 2; } a.m = g; print a.m();"),
+            (Outcome::Ok, "2\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_class_errors() {
+        assert_eq!(run("class A { init(a) {} } A();").0, Outcome::RuntimeError);
+        assert_eq!(run("class A {} A(1);").0, Outcome::RuntimeError);
+        assert_eq!(
+            run("class A {} print A().missing;").0,
+            Outcome::RuntimeError
+        );
+        assert_eq!(run("class A {} A().missing();").0, Outcome::RuntimeError);
+        assert_eq!(run("var x = 1; x.y = 2;").0, Outcome::RuntimeError);
+        assert_eq!(run("\"str\".length;").0, Outcome::RuntimeError);
+    }
+
+    #[test]
+    fn test_interpret_inheritance() {
+        assert_eq!(
+            run("class A { method() { print \"A method\"; } } class B < A { method() { print \"B method\"; } test() { super.method(); } } class C < B {} C().test();"),
+            (Outcome::Ok, "A method\n".to_string())
+        );
+        assert_eq!(
+            run("class A { init(x) { this.x = x; } get() { return this.x; } } class B < A { init() { super.init(42); } } var b = B(); print b.get();"),
+            (Outcome::Ok, "42\n".to_string())
+        );
+        assert_eq!(
+            run("class A { name() { return \"A\"; } } class B < A { name() { var f = super.name; return f() + \"B\"; } } print B().name();"),
+            (Outcome::Ok, "AB\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_inheritance_errors() {
+        assert_eq!(
+            run("var NotAClass = 1; class A < NotAClass {}").0,
+            Outcome::RuntimeError
+        );
+        assert_eq!(
+            run("class A {} class B < A { m() { super.missing(); } } B().m();").0,
+            Outcome::RuntimeError
+        );
+    }
+
+    #[test]
+    fn test_interpret_stress_gc() {
+        let source = "
+            fun counter() { var count = 0; fun inc() { count = count + 1; return count; } return inc; }
+            var c = counter(); c(); print c();
+            class Node { init(value, next) { this.value = value; this.next = next; } }
+            class Named < Node { name() { return \"node \" + this.value; } }
+            var list = nil;
+            for (var i = 0; i < 20; i = i + 1) { list = Named(\"v\" + \"x\", list); var garbage = \"g\" + \"arbage\"; }
+            var n = 0; var node = list; while (node != nil) { n = n + 1; node = node.next; }
+            print n; print list.name(); var bound = list.name; print bound();
+        ";
+        let expected = "2\n20\nnode vx\nnode vx\n".to_string();
+        assert_eq!(run(source), (Outcome::Ok, expected.clone()));
+        for mode in [GcMode::StopTheWorld, GcMode::Incremental] {
+            let config = Config {
+                gc: GcConfig {
+                    mode,
+                    stress_gc: true,
+                    log_gc: false,
+                },
+                ..Config::default()
+            };
+            assert_eq!(
+                run_with_config(source, config),
+                (Outcome::Ok, expected.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_gc_stats() {
+        let config = Config {
+            gc: GcConfig {
+                mode: GcMode::Incremental,
+                stress_gc: true,
+                log_gc: false,
+            },
+            ..Config::default()
+        };
+        let mut vm = VM::with_config(config, Box::new(std::io::sink()));
+        let result = vm.interpret(
+            "test.lox",
+            "var s = \"\"; for (var i = 0; i < 100; i = i + 1) { s = s + \"x\"; }",
+        );
+        assert!(result.is_ok());
+        let stats = vm.gc_stats();
+        assert!(stats.cycles > 0);
+        assert!(stats.pauses > stats.cycles);
+        assert!(stats.max_pause <= stats.total_pause);
+    }
+
+    #[test]
+    fn test_interpret_constant_long() {
+        let source: String = (0..300).map(|i| format!("{};", i)).collect();
+        assert_eq!(
+            run(&(source + "print 299 + 0.5;")),
+            (Outcome::Ok, "299.5\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpret_wide_operands() {
+        let literals: String = (0..300).map(|i| format!("{};", i)).collect();
+        let source = literals
+            + "var x = 1; print x;
+               fun f() { return x + 1; } print f();
+               class A { init() { this.y = 3; } get() { return this.y; } }
+               class B < A { get() { return super.get() + 1; } }
+               var b = B(); b.y = b.y + 1; print b.get();";
+        assert_eq!(run(&source), (Outcome::Ok, "1\n2\n5\n".to_string()));
+    }
+
+    #[test]
+    fn test_interpret_compile_error() {
+        let mut vm = VM::new(Config::default());
+        assert_eq!(
+            outcome(vm.interpret("test.lox", "1 +;")),
+            Outcome::CompileError
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "trace")]
+    fn test_trace_output() {
+        let trace = Output::default();
+        let config = Config {
+            trace_execution: true,
+            print_code: true,
+            trace_output: Box::new(trace.clone()),
+            ..Config::default()
+        };
+        let (result, printed) = run_with_config("fun f() { return 1; } print f();", config);
+        assert_eq!((result, printed.as_str()), (Outcome::Ok, "1\n"));
+        let traced = String::from_utf8(trace.0.borrow().clone()).unwrap();
+        assert!(traced.starts_with("== <script> ==\n0000    1 OP_CLOSURE"));
+        assert!(traced.contains("== f ==\n"));
+        assert!(traced.contains("          [ <script> ][ <fn f> ]\n0006  |   OP_CALL"));
+
+        // Nothing is traced unless asked for.
+        let trace = Output::default();
+        let config = Config {
+            trace_output: Box::new(trace.clone()),
+            ..Config::default()
+        };
+        run_with_config("print 1;", config);
+        assert!(trace.0.borrow().is_empty());
+    }
+
+    // The source underlined by the primary label of a runtime error.
+    fn runtime_error_text(source: &str) -> String {
+        let mut vm = VM::with_config(Config::default(), Box::new(std::io::sink()));
+        let Err(LoxError::Runtime(diagnostic)) = vm.interpret("test.lox", source) else {
+            panic!("the source fails at runtime");
+        };
+        let label = diagnostic
+            .labels
+            .iter()
+            .find(|label| label.primary)
+            .unwrap();
+        source[label.span.start..label.span.end].to_string()
+    }
+
+    #[test]
+    fn test_runtime_error_spans() {
+        assert_eq!(runtime_error_text("print undefinedName;"), "undefinedName");
+        assert_eq!(runtime_error_text("counter = 1 + 2;"), "counter");
+        assert_eq!(
+            runtime_error_text("class A {} A().missingMethod(1);"),
+            "missingMethod"
+        );
+        assert_eq!(
+            runtime_error_text("class A {} class B < A { f() { super.g(); } } B().f();"),
+            "g"
+        );
+        assert_eq!(runtime_error_text("var a = 1; a.b = 2;"), "b");
+        assert_eq!(runtime_error_text("print 1 + nil;"), "+");
+    }
+
+    #[test]
+    fn test_interpret_errors() {
+        let mut vm = VM::with_config(Config::default(), Box::new(std::io::sink()));
+        let Err(LoxError::Runtime(diagnostic)) = vm.interpret("test.lox", "var a;\nprint -a;")
+        else {
+            panic!("negating nil fails at runtime");
+        };
+        assert_eq!(diagnostic.code, "E0200");
+        assert_eq!(diagnostic.position("var a;\nprint -a;"), Some((2, 7)));
+
+        let Err(LoxError::Compile(diagnostic)) = vm.interpret("test.lox", "print;") else {
+            panic!("print needs an expression");
+        };
+        assert_eq!(diagnostic.code, "E0101");
+
+        // Only assignments are pointed to declarations.
+        let Err(LoxError::Runtime(diagnostic)) = vm.interpret("test.lox", "print b;") else {
+            panic!("b is not defined");
+        };
+        assert_eq!(diagnostic.code, "E0201");
+        assert!(diagnostic.help.is_empty());
+        let Err(LoxError::Runtime(diagnostic)) = vm.interpret("test.lox", "b = 1;") else {
+            panic!("b is not defined");
+        };
+        assert_eq!(
+            diagnostic.help,
+            ["assignment does not declare variables, use 'var b = ...;'"]
+        );
+    }
+}